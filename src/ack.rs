@@ -0,0 +1,352 @@
+use crate::warn;
+use nats::jetstream::AckKind;
+use std::fmt::{Debug, Formatter};
+use std::io::Result;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Source side of an acknowledgement: a JetStream ack, an offset commit...
+pub trait Acknowledge: Send {
+    /// The message has been delivered and must not be sent again
+    fn ack(&mut self) -> Result<()>;
+    /// The message could not be delivered and should be sent again
+    fn nack(&mut self) -> Result<()>;
+    /// The message was given up on, e.g. the script threw on it. Sources able to send it again
+    /// later, rather than right away, should, so that a message failing every time does not
+    /// keep the route busy.
+    fn abandon(&mut self) -> Result<()> {
+        self.nack()
+    }
+    /// Used in diagnostics only
+    fn describe(&self) -> String;
+}
+
+struct JetStreamAck(nats::Message);
+
+impl Acknowledge for JetStreamAck {
+    fn ack(&mut self) -> Result<()> {
+        self.0.ack()
+    }
+
+    fn nack(&mut self) -> Result<()> {
+        self.0.ack_kind(AckKind::Nak)
+    }
+
+    /// Left to the consumer, which redelivers once its ack wait expires, up to its max deliveries
+    fn abandon(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        self.0.subject.clone()
+    }
+}
+
+/// Handle to acknowledge a message back to the source it was read from.
+///
+/// The handle travels inside [`crate::msg::Msg`] from the reader, through the processor, to the
+/// writer, which acks it once the destination confirmed the message. A handle dropped without
+/// being acked abandons the message, so anything lost on the way, on errors or shutdown, is sent
+/// again by the source. JetStream sends it again once the ack wait of the consumer expires.
+///
+/// Sources that do not track delivery, like core NATS subscriptions, attach untracked handles
+/// that only account for the in-flight window.
+pub struct AckHandle {
+    inner: Option<Box<dyn Acknowledge>>,
+    permit: Option<Permit>,
+}
+
+impl AckHandle {
+    pub fn new(inner: Box<dyn Acknowledge>) -> Self {
+        Self {
+            inner: Some(inner),
+            permit: None,
+        }
+    }
+
+    /// Handle for sources with nothing to acknowledge, it only holds an in-flight slot.
+    pub fn untracked() -> Self {
+        Self {
+            inner: None,
+            permit: None,
+        }
+    }
+
+    /// Wraps a JetStream message so it can be acked once it has been relayed.
+    pub fn jetstream(msg: nats::Message) -> Self {
+        Self::new(Box::new(JetStreamAck(msg)))
+    }
+
+    /// Holds a slot of the in-flight window until the message is acked or nacked.
+    pub fn with_permit(mut self, permit: Permit) -> Self {
+        self.permit = Some(permit);
+        self
+    }
+
+    /// Splits the handle for a message relayed as `parts` messages. The source is told the
+    /// message was delivered once every part is acked, and to send it again as soon as one part
+    /// is nacked.
+    pub fn split(self, parts: usize) -> Vec<AckHandle> {
+        if parts == 1 {
+            return vec![self];
+        }
+
+        let split = Arc::new(Mutex::new(Split {
+            original: Some(self),
+            remaining: parts,
+        }));
+        (0..parts)
+            .map(|_| AckHandle::new(Box::new(SplitPart(Arc::clone(&split)))))
+            .collect()
+    }
+
+    /// Tells the source the message has been delivered and it must not be sent again.
+    pub fn ack(mut self) {
+        if let Some(mut inner) = self.inner.take() {
+            if let Err(e) = inner.ack() {
+                warn!(kind = e.kind(); "cannot ack message on {}: {}", inner.describe(), e);
+            }
+        }
+    }
+
+    /// Tells the source the message could not be delivered and should be sent again.
+    pub fn nack(mut self) {
+        self.nack_inner();
+    }
+
+    fn nack_inner(&mut self) {
+        if let Some(mut inner) = self.inner.take() {
+            if let Err(e) = inner.nack() {
+                warn!(kind = e.kind(); "cannot nack message on {}: {}", inner.describe(), e);
+            }
+        }
+    }
+}
+
+impl Drop for AckHandle {
+    fn drop(&mut self) {
+        if let Some(mut inner) = self.inner.take() {
+            if let Err(e) = inner.abandon() {
+                warn!(kind = e.kind(); "cannot nack message on {}: {}", inner.describe(), e);
+            }
+        }
+    }
+}
+
+impl Debug for AckHandle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.inner {
+            Some(inner) => write!(f, "AckHandle({})", inner.describe()),
+            None => write!(f, "AckHandle(untracked)"),
+        }
+    }
+}
+
+/// Shared by the parts of a split handle
+struct Split {
+    original: Option<AckHandle>,
+    remaining: usize,
+}
+
+struct SplitPart(Arc<Mutex<Split>>);
+
+impl Acknowledge for SplitPart {
+    fn ack(&mut self) -> Result<()> {
+        let mut split = self.0.lock().unwrap();
+        split.remaining -= 1;
+        if split.remaining == 0 {
+            if let Some(original) = split.original.take() {
+                original.ack();
+            }
+        }
+        Ok(())
+    }
+
+    fn nack(&mut self) -> Result<()> {
+        // The parts already delivered are sent again along with the others
+        if let Some(original) = self.0.lock().unwrap().original.take() {
+            original.nack();
+        }
+        Ok(())
+    }
+
+    /// A part dropped gives up on the whole message
+    fn abandon(&mut self) -> Result<()> {
+        let original = self.0.lock().unwrap().original.take();
+        drop(original);
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        match &self.0.lock().unwrap().original {
+            Some(original) => format!("part of {:?}", original),
+            None => "part of a settled message".to_string(),
+        }
+    }
+}
+
+/// Caps how many messages are read from the source and not yet acked or nacked.
+#[derive(Clone)]
+pub struct InFlight {
+    max: usize,
+    state: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl InFlight {
+    /// A `max` of zero does not cap anything
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            state: Arc::new((Mutex::new(0), Condvar::new())),
+        }
+    }
+
+    /// Blocks until a slot is free
+    pub fn acquire(&self) -> Permit {
+        let (count, freed) = &*self.state;
+        let mut count = count.lock().unwrap();
+        while self.max > 0 && *count >= self.max {
+            count = freed.wait(count).unwrap();
+        }
+        *count += 1;
+
+        Permit {
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    /// Messages currently holding a slot
+    pub fn len(&self) -> usize {
+        *self.state.0.lock().unwrap()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A slot of the [`InFlight`] window, given back when dropped.
+pub struct Permit {
+    state: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let (count, freed) = &*self.state;
+        *count.lock().unwrap() -= 1;
+        freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AckHandle, Acknowledge, InFlight};
+    use std::io::Result;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    struct Recorder(Arc<Mutex<Vec<&'static str>>>);
+
+    impl Acknowledge for Recorder {
+        fn ack(&mut self) -> Result<()> {
+            self.0.lock().unwrap().push("ack");
+            Ok(())
+        }
+
+        fn nack(&mut self) -> Result<()> {
+            self.0.lock().unwrap().push("nack");
+            Ok(())
+        }
+
+        fn describe(&self) -> String {
+            "recorder".to_string()
+        }
+    }
+
+    #[test]
+    fn ack_handle_settles_once() {
+        let log = Arc::new(Mutex::new(vec![]));
+
+        AckHandle::new(Box::new(Recorder(Arc::clone(&log)))).ack();
+        AckHandle::new(Box::new(Recorder(Arc::clone(&log)))).nack();
+        drop(AckHandle::new(Box::new(Recorder(Arc::clone(&log)))));
+
+        assert_eq!(*log.lock().unwrap(), vec!["ack", "nack", "nack"]);
+    }
+
+    /// Leaves redelivery to later, like JetStream
+    struct Patient(Arc<Mutex<Vec<&'static str>>>);
+
+    impl Acknowledge for Patient {
+        fn ack(&mut self) -> Result<()> {
+            self.0.lock().unwrap().push("ack");
+            Ok(())
+        }
+
+        fn nack(&mut self) -> Result<()> {
+            self.0.lock().unwrap().push("nack");
+            Ok(())
+        }
+
+        fn abandon(&mut self) -> Result<()> {
+            self.0.lock().unwrap().push("abandon");
+            Ok(())
+        }
+
+        fn describe(&self) -> String {
+            "patient".to_string()
+        }
+    }
+
+    #[test]
+    fn dropped_handles_abandon() {
+        let log = Arc::new(Mutex::new(vec![]));
+
+        AckHandle::new(Box::new(Patient(Arc::clone(&log)))).nack();
+        drop(AckHandle::new(Box::new(Patient(Arc::clone(&log)))));
+        let mut parts = AckHandle::new(Box::new(Patient(Arc::clone(&log)))).split(2);
+        parts.pop().unwrap().ack();
+        drop(parts);
+
+        assert_eq!(*log.lock().unwrap(), vec!["nack", "abandon", "abandon"]);
+    }
+
+    #[test]
+    fn split_handle_settles_with_its_parts() {
+        let log = Arc::new(Mutex::new(vec![]));
+
+        let mut parts = AckHandle::new(Box::new(Recorder(Arc::clone(&log)))).split(3);
+        parts.pop().unwrap().ack();
+        parts.pop().unwrap().ack();
+        assert!(log.lock().unwrap().is_empty());
+        parts.pop().unwrap().ack();
+        assert_eq!(*log.lock().unwrap(), vec!["ack"]);
+
+        // One part lost is enough to get the message again
+        let mut parts = AckHandle::new(Box::new(Recorder(Arc::clone(&log)))).split(2);
+        drop(parts.pop());
+        parts.pop().unwrap().ack();
+        assert_eq!(*log.lock().unwrap(), vec!["ack", "nack"]);
+    }
+
+    #[test]
+    fn in_flight_blocks_when_full() {
+        let in_flight = InFlight::new(1);
+        let permit = in_flight.acquire();
+        assert_eq!(in_flight.len(), 1);
+
+        let waiter = {
+            let in_flight = in_flight.clone();
+            thread::spawn(move || {
+                let _permit = in_flight.acquire();
+            })
+        };
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+
+        drop(permit);
+        waiter.join().unwrap();
+        assert!(in_flight.is_empty());
+    }
+}
@@ -1,12 +1,37 @@
-use clap::{App, Arg};
+use crate::config::Config;
+use crate::conn::{read_secret, Auth, ConnectOptions, Reconnect, TlsOptions};
+use crate::dead_letter::DeadLetterTarget;
+use crate::log::{Format, Level};
+use crate::mapping::{Mappings, SubjectMapping};
+use crate::origin::Origin;
+use crate::read::JetStreamSource;
+use crate::reply::RequestReply;
+use crate::route::Route;
+use crate::spill::SpillOptions;
+use crate::write::JetStreamTarget;
+use clap::{App, Arg, ArgMatches};
+use std::time::Duration;
+
+/// Name of the route built from command line flags
+pub const DEFAULT_ROUTE: &str = "default";
 
 #[derive(Debug)]
 pub struct Args {
-    pub source: String,
-    pub target: String,
-    pub topics: Vec<String>,
-    pub script: String,
+    pub routes: Vec<Route>,
+    pub origin: Origin,
     pub quiet: bool,
+    /// Full-screen stats per subject instead of the progress line
+    pub dashboard: bool,
+    /// Configuration file the routes come from, read again on SIGHUP
+    pub config: Option<String>,
+    /// Where to serve Prometheus metrics and health checks, if anywhere
+    pub metrics_addr: Option<String>,
+    /// Messages waiting in a queue before the process is not ready
+    pub backlog_threshold: usize,
+    /// How long to keep delivering what was already read once asked to stop
+    pub drain_timeout: Duration,
+    pub log_level: Level,
+    pub log_format: Format,
 }
 
 impl Args {
@@ -20,7 +45,7 @@ impl Args {
                     .short('s')
                     .long("source")
                     .takes_value(true)
-                    .required(true)
+                    .required_unless_present("config")
                     .help("Source nats to read from"),
             )
             .arg(
@@ -28,7 +53,7 @@ impl Args {
                     .short('d')
                     .long("destination")
                     .takes_value(true)
-                    .required(true)
+                    .required_unless_present("config")
                     .help("Destination nats to write to"),
             )
             .arg(
@@ -40,12 +65,125 @@ impl Args {
                     .multiple_values(true)
                     .help("Topics to relay"),
             )
+            .arg(
+                Arg::new("reverse-topics")
+                    .long("reverse-topics")
+                    .min_values(1)
+                    .takes_value(true)
+                    .multiple_values(true)
+                    .help("Topics to relay back from the destination to the source"),
+            )
+            .arg(
+                Arg::new("origin-id")
+                    .long("origin-id")
+                    .takes_value(true)
+                    .help("Id stamped on relayed messages, random by default"),
+            )
+            .arg(
+                Arg::new("max-hops")
+                    .long("max-hops")
+                    .takes_value(true)
+                    .default_value("0")
+                    .help("Skip messages relayed this many times already, 0 for no limit"),
+            )
+            .arg(
+                Arg::new("map")
+                    .short('m')
+                    .long("map")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .help("Rename subjects, e.g. 'orders.*.created=eu.{1}.created'"),
+            )
             .arg(
                 Arg::new("script")
                     .long("script")
                     .takes_value(true)
                     .help("JS script as processor"),
             )
+            .arg(
+                Arg::new("script-file")
+                    .long("script-file")
+                    .takes_value(true)
+                    .conflicts_with("script")
+                    .help("Path or http(s)/data URL of a JS or TS module as processor"),
+            )
+            .arg(
+                Arg::new("script-timeout")
+                    .long("script-timeout")
+                    .takes_value(true)
+                    .default_value("30000")
+                    .help("Milliseconds a promise returned by recv may take, 0 for no limit"),
+            )
+            .arg(
+                Arg::new("script-concurrency")
+                    .long("script-concurrency")
+                    .takes_value(true)
+                    .default_value("1")
+                    .help("Messages whose recv promise is pending at the same time"),
+            )
+            .arg(
+                Arg::new("config")
+                    .short('c')
+                    .long("config")
+                    .takes_value(true)
+                    .conflicts_with_all(&[
+                        "source",
+                        "target",
+                        "topics",
+                        "reverse-topics",
+                        "map",
+                        "script",
+                        "script-file",
+                        "script-timeout",
+                        "script-concurrency",
+                        "dead-letter",
+                        "max-in-flight",
+                        "spill-dir",
+                        "spill-max-bytes",
+                        "spill-segment-bytes",
+                        "reconnect-attempts",
+                        "reconnect-delay",
+                        "reconnect-max-delay",
+                        "reconnect-jitter",
+                        "reconnect-buffer",
+                        "request-reply",
+                        "request-timeout",
+                        "request-workers",
+                        "source-stream",
+                        "source-durable",
+                        "target-jetstream",
+                        "target-max-in-flight",
+                        "target-ack-timeout",
+                        "target-retries",
+                        "source-tls-ca",
+                        "source-tls-cert",
+                        "source-tls-key",
+                        "source-tls-required",
+                        "source-tls-server-name",
+                        "target-tls-ca",
+                        "target-tls-cert",
+                        "target-tls-key",
+                        "target-tls-required",
+                        "target-tls-server-name",
+                        "source-token",
+                        "source-token-file",
+                        "source-user",
+                        "source-password",
+                        "source-password-file",
+                        "source-nkey-seed",
+                        "source-nkey-seed-file",
+                        "source-creds",
+                        "target-token",
+                        "target-token-file",
+                        "target-user",
+                        "target-password",
+                        "target-password-file",
+                        "target-nkey-seed",
+                        "target-nkey-seed-file",
+                        "target-creds",
+                    ])
+                    .help("YAML or TOML file with the routes to relay"),
+            )
             .arg(
                 Arg::new("quiet")
                     .short('q')
@@ -53,30 +191,553 @@ impl Args {
                     .takes_value(false)
                     .help("Disable progress output"),
             )
+            .arg(
+                Arg::new("dashboard")
+                    .long("dashboard")
+                    .takes_value(false)
+                    .conflicts_with("quiet")
+                    .help("Show the busiest subjects, errors and queues full screen"),
+            )
+            .arg(
+                Arg::new("max-in-flight")
+                    .long("max-in-flight")
+                    .takes_value(true)
+                    .default_value("1024")
+                    .help("Undelivered messages before reading pauses, 0 for no limit"),
+            )
+            .arg(
+                Arg::new("spill-dir")
+                    .long("spill-dir")
+                    .takes_value(true)
+                    .help("Directory to queue messages on disk between reading and writing them"),
+            )
+            .arg(
+                Arg::new("spill-max-bytes")
+                    .long("spill-max-bytes")
+                    .takes_value(true)
+                    .default_value("1073741824")
+                    .help("Bytes queued on disk before reading pauses"),
+            )
+            .arg(
+                Arg::new("spill-segment-bytes")
+                    .long("spill-segment-bytes")
+                    .takes_value(true)
+                    .default_value("67108864")
+                    .help("Bytes per queue file, files are deleted once delivered"),
+            )
+            .arg(
+                Arg::new("dead-letter")
+                    .long("dead-letter")
+                    .takes_value(true)
+                    .help(
+                        "Where failed messages go: source:<subject>, destination:<subject> or \
+                         file:<path>",
+                    ),
+            )
+            .arg(
+                Arg::new("metrics-addr")
+                    .long("metrics-addr")
+                    .takes_value(true)
+                    .help("Address to serve metrics and health checks on, e.g. 0.0.0.0:9090"),
+            )
+            .arg(
+                Arg::new("backlog-threshold")
+                    .long("backlog-threshold")
+                    .takes_value(true)
+                    .default_value("1000")
+                    .help("Messages waiting in a queue before /readyz reports not ready"),
+            )
+            .arg(
+                Arg::new("drain-timeout")
+                    .long("drain-timeout")
+                    .takes_value(true)
+                    .default_value("10000")
+                    .help("Milliseconds to deliver in-flight messages on shutdown before exiting"),
+            )
+            .arg(
+                Arg::new("log-level")
+                    .long("log-level")
+                    .takes_value(true)
+                    .possible_values(["error", "warn", "info", "debug"])
+                    .default_value("info")
+                    .help("Least severe log entries to write"),
+            )
+            .arg(
+                Arg::new("log-format")
+                    .long("log-format")
+                    .takes_value(true)
+                    .possible_values(["text", "json"])
+                    .default_value("text")
+                    .help("Write logs as text lines or as one JSON object per line"),
+            )
+            .arg(
+                Arg::new("reconnect-attempts")
+                    .long("reconnect-attempts")
+                    .takes_value(true)
+                    .default_value("0")
+                    .help("Reconnection attempts before giving up on a server, 0 for no limit"),
+            )
+            .arg(
+                Arg::new("reconnect-delay")
+                    .long("reconnect-delay")
+                    .takes_value(true)
+                    .default_value("100")
+                    .help("Milliseconds before the first reconnection attempt, doubled after each"),
+            )
+            .arg(
+                Arg::new("reconnect-max-delay")
+                    .long("reconnect-max-delay")
+                    .takes_value(true)
+                    .default_value("10000")
+                    .help("Longest wait between reconnection attempts, in milliseconds"),
+            )
+            .arg(
+                Arg::new("reconnect-jitter")
+                    .long("reconnect-jitter")
+                    .takes_value(true)
+                    .default_value("100")
+                    .help("Random milliseconds added to every reconnection wait"),
+            )
+            .arg(
+                Arg::new("reconnect-buffer")
+                    .long("reconnect-buffer")
+                    .takes_value(true)
+                    .default_value("8388608")
+                    .help("Bytes published while disconnected, sent once reconnected"),
+            )
+            .arg(
+                Arg::new("request-reply")
+                    .long("request-reply")
+                    .takes_value(false)
+                    .conflicts_with_all(&[
+                        "script",
+                        "script-file",
+                        "source-stream",
+                        "target-jetstream",
+                    ])
+                    .help("Forward requests and relay their responses back"),
+            )
+            .arg(
+                Arg::new("request-timeout")
+                    .long("request-timeout")
+                    .takes_value(true)
+                    .default_value("5000")
+                    .help("Milliseconds to wait for the destination to answer a request"),
+            )
+            .arg(
+                Arg::new("request-workers")
+                    .long("request-workers")
+                    .takes_value(true)
+                    .default_value("16")
+                    .help("Requests waiting for their response at the same time"),
+            )
+            .arg(
+                Arg::new("source-stream")
+                    .long("source-stream")
+                    .takes_value(true)
+                    .help("Read from a durable consumer on this JetStream stream"),
+            )
+            .arg(
+                Arg::new("source-durable")
+                    .long("source-durable")
+                    .takes_value(true)
+                    .default_value("naps")
+                    .help("Durable consumer name prefix for the JetStream source, one per topic"),
+            )
+            .arg(
+                Arg::new("target-jetstream")
+                    .long("destination-jetstream")
+                    .takes_value(false)
+                    .help("Publish into JetStream and wait for the server ack"),
+            )
+            .arg(
+                Arg::new("target-max-in-flight")
+                    .long("destination-max-in-flight")
+                    .takes_value(true)
+                    .default_value("256")
+                    .help("JetStream messages waiting for an ack at the same time"),
+            )
+            .arg(
+                Arg::new("target-ack-timeout")
+                    .long("destination-ack-timeout")
+                    .takes_value(true)
+                    .default_value("5000")
+                    .help("Milliseconds to wait for a JetStream ack before retrying"),
+            )
+            .arg(
+                Arg::new("target-retries")
+                    .long("destination-retries")
+                    .takes_value(true)
+                    .default_value("3")
+                    .help("Times a JetStream publish is retried before giving up"),
+            )
+            .arg(
+                Arg::new("source-tls-ca")
+                    .long("source-tls-ca")
+                    .takes_value(true)
+                    .help("PEM CA bundle to verify the source server"),
+            )
+            .arg(
+                Arg::new("source-tls-cert")
+                    .long("source-tls-cert")
+                    .takes_value(true)
+                    .requires("source-tls-key")
+                    .help("PEM client certificate for the source"),
+            )
+            .arg(
+                Arg::new("source-tls-key")
+                    .long("source-tls-key")
+                    .takes_value(true)
+                    .requires("source-tls-cert")
+                    .help("PEM client private key for the source"),
+            )
+            .arg(
+                Arg::new("source-tls-required")
+                    .long("source-tls-required")
+                    .takes_value(false)
+                    .help("Require TLS on the source connection"),
+            )
+            .arg(
+                Arg::new("source-tls-server-name")
+                    .long("source-tls-server-name")
+                    .takes_value(true)
+                    .help("Name to verify the source certificate against"),
+            )
+            .arg(
+                Arg::new("target-tls-ca")
+                    .long("destination-tls-ca")
+                    .takes_value(true)
+                    .help("PEM CA bundle to verify the destination server"),
+            )
+            .arg(
+                Arg::new("target-tls-cert")
+                    .long("destination-tls-cert")
+                    .takes_value(true)
+                    .requires("target-tls-key")
+                    .help("PEM client certificate for the destination"),
+            )
+            .arg(
+                Arg::new("target-tls-key")
+                    .long("destination-tls-key")
+                    .takes_value(true)
+                    .requires("target-tls-cert")
+                    .help("PEM client private key for the destination"),
+            )
+            .arg(
+                Arg::new("target-tls-required")
+                    .long("destination-tls-required")
+                    .takes_value(false)
+                    .help("Require TLS on the destination connection"),
+            )
+            .arg(
+                Arg::new("target-tls-server-name")
+                    .long("destination-tls-server-name")
+                    .takes_value(true)
+                    .help("Name to verify the destination certificate against"),
+            )
+            .arg(
+                Arg::new("source-token")
+                    .long("source-token")
+                    .takes_value(true)
+                    .env("NAPS_SOURCE_TOKEN")
+                    .hide_env_values(true)
+                    .help("Token to authenticate against the source"),
+            )
+            .arg(
+                Arg::new("source-token-file")
+                    .long("source-token-file")
+                    .takes_value(true)
+                    .help("File holding the source token"),
+            )
+            .arg(
+                Arg::new("source-user")
+                    .long("source-user")
+                    .takes_value(true)
+                    .env("NAPS_SOURCE_USER")
+                    .hide_env_values(true)
+                    .help("User to authenticate against the source"),
+            )
+            .arg(
+                Arg::new("source-password")
+                    .long("source-password")
+                    .takes_value(true)
+                    .env("NAPS_SOURCE_PASSWORD")
+                    .hide_env_values(true)
+                    .help("Password for the source user"),
+            )
+            .arg(
+                Arg::new("source-password-file")
+                    .long("source-password-file")
+                    .takes_value(true)
+                    .help("File holding the source password"),
+            )
+            .arg(
+                Arg::new("source-nkey-seed")
+                    .long("source-nkey-seed")
+                    .takes_value(true)
+                    .env("NAPS_SOURCE_NKEY_SEED")
+                    .hide_env_values(true)
+                    .help("NKey seed to authenticate against the source"),
+            )
+            .arg(
+                Arg::new("source-nkey-seed-file")
+                    .long("source-nkey-seed-file")
+                    .takes_value(true)
+                    .help("File holding the source NKey seed"),
+            )
+            .arg(
+                Arg::new("source-creds")
+                    .long("source-creds")
+                    .takes_value(true)
+                    .env("NAPS_SOURCE_CREDS")
+                    .hide_env_values(true)
+                    .help("JWT .creds file for the source"),
+            )
+            .arg(
+                Arg::new("target-token")
+                    .long("destination-token")
+                    .takes_value(true)
+                    .env("NAPS_DESTINATION_TOKEN")
+                    .hide_env_values(true)
+                    .help("Token to authenticate against the destination"),
+            )
+            .arg(
+                Arg::new("target-token-file")
+                    .long("destination-token-file")
+                    .takes_value(true)
+                    .help("File holding the destination token"),
+            )
+            .arg(
+                Arg::new("target-user")
+                    .long("destination-user")
+                    .takes_value(true)
+                    .env("NAPS_DESTINATION_USER")
+                    .hide_env_values(true)
+                    .help("User to authenticate against the destination"),
+            )
+            .arg(
+                Arg::new("target-password")
+                    .long("destination-password")
+                    .takes_value(true)
+                    .env("NAPS_DESTINATION_PASSWORD")
+                    .hide_env_values(true)
+                    .help("Password for the destination user"),
+            )
+            .arg(
+                Arg::new("target-password-file")
+                    .long("destination-password-file")
+                    .takes_value(true)
+                    .help("File holding the destination password"),
+            )
+            .arg(
+                Arg::new("target-nkey-seed")
+                    .long("destination-nkey-seed")
+                    .takes_value(true)
+                    .env("NAPS_DESTINATION_NKEY_SEED")
+                    .hide_env_values(true)
+                    .help("NKey seed to authenticate against the destination"),
+            )
+            .arg(
+                Arg::new("target-nkey-seed-file")
+                    .long("destination-nkey-seed-file")
+                    .takes_value(true)
+                    .help("File holding the destination NKey seed"),
+            )
+            .arg(
+                Arg::new("target-creds")
+                    .long("destination-creds")
+                    .takes_value(true)
+                    .env("NAPS_DESTINATION_CREDS")
+                    .hide_env_values(true)
+                    .help("JWT .creds file for the destination"),
+            )
             .get_matches();
 
-        let source = matches.value_of("source").unwrap_or_default().to_string();
-        let target = matches.value_of("target").unwrap_or_default().to_string();
-        let topics: Vec<String> = matches
-            .values_of("topics")
-            .unwrap_or_default()
-            .collect::<Vec<&str>>()
-            .iter()
-            .map(|&x| String::from(x))
-            .collect::<Vec<String>>();
         let quiet = matches.is_present("quiet");
-        let script = matches.value_of("script").unwrap_or_default().to_string();
+        let drain_timeout = Duration::from_millis(number(&matches, "drain-timeout"));
+
+        let routes = match matches.value_of("config") {
+            Some(path) => Config::load(path)
+                .and_then(Config::into_routes)
+                .unwrap_or_else(|e| {
+                    eprintln!("cannot load config {}: {}", path, e);
+                    std::process::exit(2);
+                }),
+            None => vec![route(&matches)],
+        };
+
+        let origin = Origin::new(
+            matches
+                .value_of("origin-id")
+                .map(String::from)
+                .unwrap_or_else(nuid::next),
+            number(&matches, "max-hops"),
+        );
 
         Self {
-            source,
-            target,
-            topics,
-            script,
+            routes,
+            origin,
             quiet,
+            dashboard: matches.is_present("dashboard"),
+            config: matches.value_of("config").map(String::from),
+            metrics_addr: matches.value_of("metrics-addr").map(String::from),
+            backlog_threshold: number(&matches, "backlog-threshold"),
+            drain_timeout,
+            log_level: matches.value_of_t("log-level").unwrap_or_else(|e| e.exit()),
+            log_format: matches
+                .value_of_t("log-format")
+                .unwrap_or_else(|e| e.exit()),
         }
     }
+}
+
+/// Builds the single route described by command line flags
+fn route(matches: &ArgMatches) -> Route {
+    let source = connect_options(matches, "source");
+    let target = connect_options(matches, "target");
+    let topics: Vec<String> = matches
+        .values_of("topics")
+        .unwrap_or_default()
+        .collect::<Vec<&str>>()
+        .iter()
+        .map(|&x| String::from(x))
+        .collect::<Vec<String>>();
+    let reverse_topics: Vec<String> = matches
+        .values_of("reverse-topics")
+        .unwrap_or_default()
+        .map(String::from)
+        .collect();
+    let mappings = matches
+        .values_of("map")
+        .unwrap_or_default()
+        .map(|m| {
+            m.parse::<SubjectMapping>().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            })
+        })
+        .collect();
+    let source_jetstream = matches
+        .value_of("source-stream")
+        .map(|stream| JetStreamSource {
+            stream: stream.to_string(),
+            durable: matches
+                .value_of("source-durable")
+                .unwrap_or_default()
+                .to_string(),
+        });
+    let target_jetstream = if matches.is_present("target-jetstream") {
+        Some(JetStreamTarget {
+            max_in_flight: number(matches, "target-max-in-flight"),
+            ack_timeout: Duration::from_millis(number(matches, "target-ack-timeout")),
+            retries: number(matches, "target-retries"),
+        })
+    } else {
+        None
+    };
+    let max_in_flight = number(matches, "max-in-flight");
+    let spill = matches.value_of("spill-dir").map(|dir| SpillOptions {
+        dir: dir.into(),
+        max_bytes: number(matches, "spill-max-bytes"),
+        segment_bytes: number(matches, "spill-segment-bytes"),
+    });
+    let request_reply = if matches.is_present("request-reply") {
+        Some(RequestReply {
+            timeout: Duration::from_millis(number(matches, "request-timeout")),
+            workers: number(matches, "request-workers"),
+        })
+    } else {
+        None
+    };
+    let script = matches.value_of("script").unwrap_or_default().to_string();
+    let script_file = matches.value_of("script-file").map(String::from);
+    let script_timeout = Duration::from_millis(number(matches, "script-timeout"));
+    let script_concurrency = number(matches, "script-concurrency");
+    let dead_letter = matches.value_of("dead-letter").map(|_| {
+        matches
+            .value_of_t::<DeadLetterTarget>("dead-letter")
+            .unwrap_or_else(|e| e.exit())
+    });
+
+    let route = Route {
+        name: DEFAULT_ROUTE.to_string(),
+        source,
+        target,
+        topics,
+        reverse_topics,
+        mappings: Mappings(mappings),
+        source_jetstream,
+        target_jetstream,
+        max_in_flight,
+        spill,
+        request_reply,
+        script,
+        script_file,
+        script_timeout,
+        script_concurrency,
+        dead_letter,
+    };
+    route.validate().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+
+    route
+}
+
+fn number<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> T {
+    matches.value_of_t(name).unwrap_or_else(|e| e.exit())
+}
+
+fn connect_options(matches: &ArgMatches, side: &str) -> ConnectOptions {
+    let value = |name: &str| {
+        matches
+            .value_of(format!("{}-{}", side, name).as_str())
+            .map(String::from)
+    };
+    // Secrets can be given inline, through the environment or in a file
+    let secret = |name: &str| {
+        value(name).or_else(|| {
+            value(format!("{}-file", name).as_str()).map(|path| {
+                read_secret(path.as_str()).unwrap_or_else(|e| {
+                    eprintln!("cannot read {} {} from {}: {}", side, name, path, e);
+                    std::process::exit(2);
+                })
+            })
+        })
+    };
+
+    let auth = if let Some(creds) = value("creds") {
+        Auth::Credentials(creds)
+    } else if let Some(seed) = secret("nkey-seed") {
+        Auth::NKey(seed)
+    } else if let Some(token) = secret("token") {
+        Auth::Token(token)
+    } else if let Some(user) = value("user") {
+        Auth::UserPassword {
+            user,
+            password: secret("password").unwrap_or_default(),
+        }
+    } else {
+        Auth::None
+    };
 
-    pub fn has_script(&self) -> bool {
-        return !self.script.is_empty();
+    ConnectOptions {
+        url: matches.value_of(side).unwrap_or_default().to_string(),
+        tls: TlsOptions {
+            ca: value("tls-ca"),
+            cert: value("tls-cert"),
+            key: value("tls-key"),
+            required: matches.is_present(format!("{}-tls-required", side).as_str()),
+            server_name: value("tls-server-name"),
+        },
+        auth,
+        reconnect: Reconnect {
+            attempts: number(matches, "reconnect-attempts"),
+            delay: Duration::from_millis(number(matches, "reconnect-delay")),
+            max_delay: Duration::from_millis(number(matches, "reconnect-max-delay")),
+            jitter: Duration::from_millis(number(matches, "reconnect-jitter")),
+            buffer_size: number(matches, "reconnect-buffer"),
+        },
     }
 }
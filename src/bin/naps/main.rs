@@ -1,109 +1,574 @@
-use crossbeam::channel::{bounded, unbounded, Receiver};
-use deno_core::futures::TryFutureExt;
-use naps::read::read_loop;
-use naps::write::write_loop;
-use naps::{args::Args, process, stats};
+use crossbeam::channel::{bounded, never, unbounded, Sender};
+use naps::conn::Side;
+use naps::dead_letter::{dead_letter_loop, DeadLetterTarget, DeadLetterWriter, DeadLetters};
+use naps::http::http_loop;
+use naps::mapping::Mappings;
+use naps::metrics::{Metrics, RouteMetrics};
+use naps::msg::Msg;
+use naps::origin::Origin;
+use naps::process::ProcessOptions;
+use naps::read::{read_loop, ReadOptions};
+use naps::reload::{reload_loop, Reloadable, Updates};
+use naps::reply::reply_loop;
+use naps::route::Route;
+use naps::shutdown::Drain;
+use naps::spill::{drain_loop, spill_loop, SpillOptions, SpillQueue};
+use naps::stats::{Event, View};
+use naps::write::{write_loop, WriteOptions};
+use naps::{args::Args, log, process, stats};
+use naps::{error, info};
+use nats::Connection;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
 use signal_hook::flag;
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
+use std::net::TcpListener;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Time left to the loops to tally what they drop once the drain expired
+const DRAIN_GRACE: Duration = Duration::from_millis(500);
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    log::init(args.log_level, args.log_format);
     let shutdown = Arc::new(AtomicBool::new(false));
 
-    flag::register(signal_hook::consts::SIGTERM, Arc::clone(&shutdown))?;
+    let reload = Arc::new(AtomicBool::new(false));
+
+    for signal in [SIGTERM, SIGINT] {
+        // A second signal while draining exits right away
+        flag::register_conditional_shutdown(signal, 1, Arc::clone(&shutdown))?;
+        flag::register(signal, Arc::clone(&shutdown))?;
+    }
+    flag::register(SIGHUP, Arc::clone(&reload))?;
+
+    let drain = Drain::new(Arc::clone(&shutdown));
+    let drain_watchdog = drain.clone();
+    let shutdown_arc_watchdog = Arc::clone(&shutdown);
+    let drain_timeout = args.drain_timeout;
+    thread::Builder::new()
+        .name("drain".into())
+        .spawn(move || watchdog(drain_watchdog, shutdown_arc_watchdog, drain_timeout))
+        .unwrap();
+
+    let metrics = Metrics::default();
 
-    return if args.has_script() {
-        proxy_and_process(args, shutdown)
+    let (stats_sc, stats_rc) = unbounded();
+    let shutdown_arc_stats = Arc::clone(&shutdown);
+    let view = if args.dashboard {
+        View::Dashboard
+    } else if args.quiet {
+        View::Quiet
     } else {
-        proxy(args, shutdown)
+        View::Progress
     };
-}
+    let metrics_stats = metrics.clone();
+    let stats_handle = thread::Builder::new()
+        .name("stats".into())
+        .spawn(move || stats::stats_loop(view, metrics_stats, stats_rc, shutdown_arc_stats))
+        .unwrap();
 
-fn proxy(args: Args, shutdown_arc: Arc<AtomicBool>) -> Result<()> {
-    let (stats_sc, stats_rc) = unbounded();
-    let (write_sc, write_rc) = bounded(1024);
+    if let Some(addr) = &args.metrics_addr {
+        let listener = TcpListener::bind(addr)?;
+        info!("serving metrics on {}", addr);
+        let metrics = metrics.clone();
+        let shutdown_arc_http = Arc::clone(&shutdown);
+        let backlog = args.backlog_threshold;
+        thread::Builder::new()
+            .name("http".into())
+            .spawn(move || http_loop(listener, metrics, backlog, shutdown_arc_http))
+            .unwrap();
+    }
 
-    let shutdown_arc_read = Arc::clone(&shutdown_arc);
-    let shutdown_arc_stats = Arc::clone(&shutdown_arc);
-    let shutdown_arc_write = Arc::clone(&shutdown_arc);
-
-    let read_handle = thread::spawn(move || {
-        read_loop(
-            args.source,
-            args.topics,
-            stats_sc,
-            write_sc,
-            shutdown_arc_read,
-        )
-    });
-    let stats_handle =
-        thread::spawn(move || stats::stats_loop(args.quiet, stats_rc, shutdown_arc_stats));
-    let write_handle = thread::spawn(move || write_loop(args.target, write_rc, shutdown_arc_write));
+    // One pipeline per route, all of them sharing the stats loop
+    let mut reloadables = Vec::with_capacity(args.routes.len());
+    let route_handles: Vec<_> = args
+        .routes
+        .into_iter()
+        .map(|route| {
+            let (reloadable, updates) = Reloadable::new(&route);
+            reloadables.push(reloadable);
+            let metrics = metrics.route(&route.name);
+            let metrics_stop = metrics.clone();
+            // One-way relays leave headers alone, so they can be chained
+            let origin = route.is_bidirectional().then(|| args.origin.clone());
+            let stats_sc = stats_sc.clone();
+            let shutdown = Arc::clone(&shutdown);
+            let drain = drain.clone();
+            thread::Builder::new()
+                .name(route.name.clone())
+                .spawn(move || {
+                    let result = if route.request_reply.is_some() {
+                        reply_loop(route, metrics, stats_sc, shutdown)
+                    } else if route.has_script() {
+                        proxy_and_process(
+                            route, origin, updates, metrics, stats_sc, shutdown, drain,
+                        )
+                    } else {
+                        proxy(route, origin, updates, metrics, stats_sc, shutdown, drain)
+                    };
+                    // The other routes keep going, health checks tell this one is gone
+                    if let Err(e) = &result {
+                        metrics_stop.stop(&e.to_string());
+                    }
+                    result
+                })
+                .unwrap()
+        })
+        .collect();
+    drop(stats_sc);
+
+    let shutdown_arc_reload = Arc::clone(&shutdown);
+    let config = args.config;
+    thread::Builder::new()
+        .name("reload".into())
+        .spawn(move || reload_loop(config, reloadables, reload, shutdown_arc_reload))
+        .unwrap();
 
     // crash if any threads have crashed
-    // `.join()` returns a `thread::Result<io::Result<()>>`
-    let read_io_result = read_handle.join().unwrap();
+    let route_results: Vec<Result<()>> = route_handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect();
     let stats_io_result = stats_handle.join().unwrap();
-    let write_io_result = write_handle.join().unwrap();
 
-    // return an error if any thread returned an error
-    read_io_result?;
+    if shutdown.load(Ordering::Relaxed) {
+        info!("drained: {}", drain);
+    }
+
+    // return an error if any route returned an error
+    for result in route_results {
+        result?;
+    }
     stats_io_result?;
-    write_io_result?;
 
     Ok(())
 }
 
-fn proxy_and_process(args: Args, shutdown_arc: Arc<AtomicBool>) -> Result<()> {
-    let Args {
-        source,
-        target,
+/// Gives the loops `timeout` to drain once shutdown was requested, then exits regardless.
+fn watchdog(drain: Drain, shutdown_arc: Arc<AtomicBool>, timeout: Duration) {
+    let pause = Duration::from_millis(100);
+
+    while !shutdown_arc.load(Ordering::Relaxed) {
+        thread::sleep(pause);
+    }
+    info!("draining in-flight messages, {:?} at most", timeout);
+
+    thread::sleep(timeout);
+    drain.expire();
+    thread::sleep(DRAIN_GRACE);
+
+    error!(kind = "timeout"; "drain timed out: {}", drain);
+    std::process::exit(1);
+}
+
+/// Waits for every loop of a route, but returns the first error as soon as a loop fails
+/// rather than once the loops joined before it exited.
+fn wait(handles: Vec<JoinHandle<Result<()>>>) -> Result<()> {
+    let (done_sc, done_rc) = unbounded();
+    let count = handles.len();
+    for handle in handles {
+        let done_sc = done_sc.clone();
+        thread::spawn(move || done_sc.send(handle.join()));
+    }
+
+    for _ in 0..count {
+        // crash if any threads have crashed
+        // `.join()` returns a `thread::Result<io::Result<()>>`
+        let result = done_rc.recv().unwrap();
+        result.unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+    }
+
+    Ok(())
+}
+
+/// Both directions of a route share the same connections
+fn connect(
+    route: &Route,
+    metrics: &RouteMetrics,
+    stats_sc: &Sender<Event>,
+) -> Result<(Connection, Connection)> {
+    let source_nc = route.source.connect(Side::Source, stats_sc, metrics)?;
+    info!(route = route.name; "source connected");
+    let target_nc = route.target.connect(Side::Destination, stats_sc, metrics)?;
+    info!(route = route.name; "target connected");
+
+    Ok((source_nc, target_nc))
+}
+
+/// Relays the reverse topics from the target back to the source, as is
+fn reverse(
+    route: &Route,
+    origin: Option<&Origin>,
+    (source_nc, target_nc): (Connection, Connection),
+    metrics: &RouteMetrics,
+    stats_sc: &Sender<Event>,
+    shutdown_arc: &Arc<AtomicBool>,
+    drain: &Drain,
+) -> Vec<JoinHandle<Result<()>>> {
+    if route.reverse_topics.is_empty() {
+        return vec![];
+    }
+
+    let read_opts = ReadOptions {
+        topics: route.reverse_topics.clone(),
+        jetstream: None,
+        max_in_flight: route.max_in_flight,
+        origin: origin.cloned(),
+        route: route.name.clone(),
+        metrics: metrics.clone(),
+    };
+    let write_opts = WriteOptions {
+        jetstream: None,
+        mappings: Mappings::default(),
+        origin: origin.cloned(),
+        route: route.name.clone(),
+        metrics: metrics.clone(),
+        dead_letters: DeadLetters::default(),
+    };
+
+    let (write_sc, write_rc) = bounded(1024);
+    let write_rc = metrics.watch("reverse-write", write_rc);
+    let stats_sc_read = stats_sc.clone();
+    let stats_sc_write = stats_sc.clone();
+
+    let shutdown_arc_read = Arc::clone(shutdown_arc);
+    let drain_write = drain.clone();
+
+    let read_handle = thread::Builder::new()
+        .name(format!("{}-reverse-read", route.name))
+        .spawn(move || {
+            read_loop(
+                target_nc,
+                read_opts,
+                stats_sc_read,
+                write_sc,
+                never(),
+                shutdown_arc_read,
+            )
+        })
+        .unwrap();
+    let write_handle = thread::Builder::new()
+        .name(format!("{}-reverse-write", route.name))
+        .spawn(move || {
+            write_loop(
+                source_nc,
+                write_opts,
+                &write_rc,
+                stats_sc_write,
+                drain_write,
+            )
+        })
+        .unwrap();
+
+    vec![read_handle, write_handle]
+}
+
+/// Puts the disk queue in front of `next_sc` if the route spills, returns where to send messages
+fn spill_to_disk(
+    name: &str,
+    options: Option<SpillOptions>,
+    next_sc: Sender<Msg>,
+    metrics: &RouteMetrics,
+    shutdown_arc: &Arc<AtomicBool>,
+    drain: &Drain,
+) -> Result<(Sender<Msg>, Vec<JoinHandle<Result<()>>>)> {
+    let options = match options {
+        Some(options) => options,
+        None => return Ok((next_sc, vec![])),
+    };
+
+    let queue = SpillQueue::open(&options)?;
+    info!(route = name; "spilling to {}", options.dir.display());
+
+    let (spill_sc, spill_rc) = bounded(1024);
+    let spill_rc = metrics.watch("spill", spill_rc);
+    let queue_drain = queue.clone();
+    let drain_spill = drain.clone();
+    let shutdown_arc_drain = Arc::clone(shutdown_arc);
+
+    let spill_handle = thread::Builder::new()
+        .name(format!("{}-spill", name))
+        .spawn(move || spill_loop(queue, &spill_rc, drain_spill))
+        .unwrap();
+    let drain_handle = thread::Builder::new()
+        .name(format!("{}-drain", name))
+        .spawn(move || drain_loop(queue_drain, next_sc, shutdown_arc_drain))
+        .unwrap();
+
+    Ok((spill_sc, vec![spill_handle, drain_handle]))
+}
+
+/// Starts storing failed messages if the route says where, returns what to hand them to
+fn dead_letter(
+    name: &str,
+    target: Option<DeadLetterTarget>,
+    connections: (&Connection, &Connection),
+    metrics: &RouteMetrics,
+    drain: &Drain,
+) -> Result<(DeadLetters, Vec<JoinHandle<Result<()>>>)> {
+    let target = match target {
+        Some(target) => target,
+        None => return Ok((DeadLetters::default(), vec![])),
+    };
+
+    let writer = DeadLetterWriter::open(&target, connections)?;
+    info!(route = name; "dead letters go to {:?}", target);
+
+    let (dead_letter_sc, dead_letter_rc) = bounded(1024);
+    let dead_letter_rc = metrics.watch("dead-letter", dead_letter_rc);
+    let metrics = metrics.clone();
+    let drain = drain.clone();
+
+    let handle = thread::Builder::new()
+        .name(format!("{}-dead-letter", name))
+        .spawn(move || dead_letter_loop(writer, &dead_letter_rc, metrics, drain))
+        .unwrap();
+
+    Ok((DeadLetters::new(name, dead_letter_sc), vec![handle]))
+}
+
+fn proxy(
+    route: Route,
+    origin: Option<Origin>,
+    updates: Updates,
+    metrics: RouteMetrics,
+    stats_sc: Sender<Event>,
+    shutdown_arc: Arc<AtomicBool>,
+    drain: Drain,
+) -> Result<()> {
+    let (source_nc, target_nc) = connect(&route, &metrics, &stats_sc)?;
+    let reverse_handles = reverse(
+        &route,
+        origin.as_ref(),
+        (source_nc.clone(), target_nc.clone()),
+        &metrics,
+        &stats_sc,
+        &shutdown_arc,
+        &drain,
+    );
+
+    let Route {
+        name,
+        topics,
+        mappings,
+        source_jetstream,
+        target_jetstream,
+        max_in_flight,
+        spill: spill_options,
+        dead_letter: dead_letter_target,
+        ..
+    } = route;
+    let (dead_letters, dead_letter_handles) = dead_letter(
+        &name,
+        dead_letter_target,
+        (&source_nc, &target_nc),
+        &metrics,
+        &drain,
+    )?;
+
+    let read_opts = ReadOptions {
         topics,
+        jetstream: source_jetstream,
+        max_in_flight,
+        origin: origin.clone(),
+        route: name.clone(),
+        metrics: metrics.clone(),
+    };
+    let write_opts = WriteOptions {
+        jetstream: target_jetstream,
+        mappings,
+        origin,
+        route: name.clone(),
+        metrics: metrics.clone(),
+        dead_letters,
+    };
+
+    let (write_sc, write_rc) = bounded(1024);
+    let write_rc = metrics.watch("write", write_rc);
+    let (read_sc, spill_handles) = spill_to_disk(
+        &name,
+        spill_options,
+        write_sc,
+        &metrics,
+        &shutdown_arc,
+        &drain,
+    )?;
+    let stats_sc_write = stats_sc.clone();
+
+    let shutdown_arc_read = Arc::clone(&shutdown_arc);
+    let drain_write = drain.clone();
+
+    let read_handle = thread::Builder::new()
+        .name(format!("{}-read", name))
+        .spawn(move || {
+            read_loop(
+                source_nc,
+                read_opts,
+                stats_sc,
+                read_sc,
+                updates.topics,
+                shutdown_arc_read,
+            )
+        })
+        .unwrap();
+    let write_handle = thread::Builder::new()
+        .name(format!("{}-write", name))
+        .spawn(move || {
+            write_loop(
+                target_nc,
+                write_opts,
+                &write_rc,
+                stats_sc_write,
+                drain_write,
+            )
+        })
+        .unwrap();
+
+    let handles = vec![read_handle, write_handle]
+        .into_iter()
+        .chain(spill_handles)
+        .chain(reverse_handles)
+        .chain(dead_letter_handles)
+        .collect();
+    wait(handles)
+}
+
+fn proxy_and_process(
+    route: Route,
+    origin: Option<Origin>,
+    updates: Updates,
+    metrics: RouteMetrics,
+    stats_sc: Sender<Event>,
+    shutdown_arc: Arc<AtomicBool>,
+    drain: Drain,
+) -> Result<()> {
+    let (source_nc, target_nc) = connect(&route, &metrics, &stats_sc)?;
+    let reverse_handles = reverse(
+        &route,
+        origin.as_ref(),
+        (source_nc.clone(), target_nc.clone()),
+        &metrics,
+        &stats_sc,
+        &shutdown_arc,
+        &drain,
+    );
+
+    let Route {
+        name,
+        topics,
+        mappings,
+        source_jetstream,
+        target_jetstream,
+        max_in_flight,
+        spill: spill_options,
         script,
-        quiet,
-    } = args;
+        script_file,
+        script_timeout,
+        script_concurrency,
+        dead_letter: dead_letter_target,
+        ..
+    } = route;
+    let (dead_letters, dead_letter_handles) = dead_letter(
+        &name,
+        dead_letter_target,
+        (&source_nc, &target_nc),
+        &metrics,
+        &drain,
+    )?;
+    let process_opts = ProcessOptions {
+        route: name.clone(),
+        metrics: metrics.clone(),
+        dead_letters: dead_letters.clone(),
+        timeout: script_timeout,
+        concurrency: script_concurrency,
+        script_file,
+    };
+
+    let read_opts = ReadOptions {
+        topics,
+        jetstream: source_jetstream,
+        max_in_flight,
+        origin: origin.clone(),
+        route: name.clone(),
+        metrics: metrics.clone(),
+    };
+    let write_opts = WriteOptions {
+        jetstream: target_jetstream,
+        mappings,
+        origin,
+        route: name.clone(),
+        metrics: metrics.clone(),
+        dead_letters,
+    };
 
-    let (stats_sc, stats_rc) = unbounded();
     let (process_sc, process_rc) = unbounded();
+    let process_rc = metrics.watch("process", process_rc);
+    let (read_sc, spill_handles) = spill_to_disk(
+        &name,
+        spill_options,
+        process_sc,
+        &metrics,
+        &shutdown_arc,
+        &drain,
+    )?;
     let (write_sc, write_rc) = bounded(1024);
+    let write_rc = metrics.watch("write", write_rc);
+    let stats_sc_process = stats_sc.clone();
+    let stats_sc_write = stats_sc.clone();
 
     let shutdown_arc_read = Arc::clone(&shutdown_arc);
-    let shutdown_arc_process = Arc::clone(&shutdown_arc);
-    let shutdown_arc_stats = Arc::clone(&shutdown_arc);
-    let shutdown_arc_write = Arc::clone(&shutdown_arc);
+    let drain_process = drain.clone();
+    let drain_write = drain.clone();
 
     let read_handle = thread::Builder::new()
-        .name("read".into())
-        .spawn(move || read_loop(source, topics, stats_sc, process_sc, shutdown_arc_read))
-        .unwrap();
-    let stats_handle = thread::Builder::new()
-        .name("stats".into())
-        .spawn(move || stats::stats_loop(quiet, stats_rc, shutdown_arc_stats))
+        .name(format!("{}-read", name))
+        .spawn(move || {
+            read_loop(
+                source_nc,
+                read_opts,
+                stats_sc,
+                read_sc,
+                updates.topics,
+                shutdown_arc_read,
+            )
+        })
         .unwrap();
     let process_handle = thread::Builder::new()
-        .name("process".into())
-        .spawn(move || process::process_loop(script, process_rc, write_sc, shutdown_arc_process))
+        .name(format!("{}-process", name))
+        .spawn(move || {
+            process::process_loop(
+                script,
+                process_opts,
+                &process_rc,
+                write_sc,
+                stats_sc_process,
+                updates.script,
+                drain_process,
+            )
+            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))
+        })
         .unwrap();
     let write_handle = thread::Builder::new()
-        .name("write".into())
-        .spawn(move || write_loop(target, write_rc, shutdown_arc_write))
+        .name(format!("{}-write", name))
+        .spawn(move || {
+            write_loop(
+                target_nc,
+                write_opts,
+                &write_rc,
+                stats_sc_write,
+                drain_write,
+            )
+        })
         .unwrap();
 
-    // crash if any threads have crashed
-    // `.join()` returns a `thread::Result<io::Result<()>>`
-    let read_io_result = read_handle.join().unwrap();
-    let process_io_result = process_handle.join();
-    let stats_io_result = stats_handle.join().unwrap();
-    let write_io_result = write_handle.join().unwrap();
-
-    // return an error if any thread returned an error
-    read_io_result?;
-    stats_io_result?;
-    write_io_result?;
-    process_io_result.unwrap_or_else(|e| Ok(()));
-
-    Ok(())
+    let handles = vec![read_handle, process_handle, write_handle]
+        .into_iter()
+        .chain(spill_handles)
+        .chain(reverse_handles)
+        .chain(dead_letter_handles)
+        .collect();
+    wait(handles)
 }
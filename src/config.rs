@@ -0,0 +1,699 @@
+use crate::conn::{read_secret, Auth, ConnectOptions, Reconnect, TlsOptions};
+use crate::dead_letter::DeadLetterTarget;
+use crate::mapping::{Mappings, SubjectMapping};
+use crate::read::JetStreamSource;
+use crate::reply::RequestReply;
+use crate::route::Route;
+use crate::spill::SpillOptions;
+use crate::write::JetStreamTarget;
+use serde::Deserialize;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::time::Duration;
+
+/// Contents of the `--config` file, in YAML or TOML.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub routes: Vec<RouteConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub name: String,
+    pub source: SourceConfig,
+    pub destination: DestinationConfig,
+    pub subjects: Vec<String>,
+    /// Subjects relayed from the destination back to the source
+    #[serde(default)]
+    pub reverse_subjects: Vec<String>,
+    #[serde(default)]
+    pub mappings: Vec<MappingConfig>,
+    /// JS or TS code, same as `--script`
+    #[serde(default)]
+    pub script: Option<String>,
+    /// Path or URL of the script module, same as `--script-file`
+    #[serde(default)]
+    pub script_file: Option<String>,
+    #[serde(default = "default_script_timeout_ms")]
+    pub script_timeout_ms: u64,
+    #[serde(default = "default_script_concurrency")]
+    pub script_concurrency: usize,
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+    #[serde(default)]
+    pub spill: Option<SpillConfig>,
+    #[serde(default)]
+    pub request_reply: Option<RequestReplyConfig>,
+    #[serde(default)]
+    pub dead_letter: Option<DeadLetterConfig>,
+}
+
+/// A subject on either side, or a local file
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeadLetterConfig {
+    Source(String),
+    Destination(String),
+    File(String),
+}
+
+impl From<DeadLetterConfig> for DeadLetterTarget {
+    fn from(config: DeadLetterConfig) -> Self {
+        match config {
+            DeadLetterConfig::Source(subject) => Self::Source(subject),
+            DeadLetterConfig::Destination(subject) => Self::Destination(subject),
+            DeadLetterConfig::File(path) => Self::File(path.into()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestReplyConfig {
+    #[serde(default = "default_request_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "default_request_workers")]
+    pub workers: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpillConfig {
+    pub dir: String,
+    #[serde(default = "default_spill_max_bytes")]
+    pub max_bytes: u64,
+    #[serde(default = "default_spill_segment_bytes")]
+    pub segment_bytes: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MappingConfig {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    pub url: String,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    #[serde(default)]
+    pub jetstream: Option<JetStreamSourceConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DestinationConfig {
+    pub url: String,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    #[serde(default)]
+    pub jetstream: Option<JetStreamTargetConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub ca: Option<String>,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    #[serde(default)]
+    pub required: bool,
+    pub server_name: Option<String>,
+}
+
+/// Secrets are given inline (`value`), read from a `file` or from an `env` variable
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Secret {
+    Value(String),
+    File(String),
+    Env(String),
+}
+
+impl Secret {
+    fn resolve(&self) -> Result<String> {
+        match self {
+            Secret::Value(value) => Ok(value.clone()),
+            Secret::File(path) => read_secret(path),
+            Secret::Env(name) => std::env::var(name)
+                .map_err(|e| Error::new(ErrorKind::NotFound, format!("env var {}: {}", name, e))),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    pub creds: Option<String>,
+    pub nkey_seed: Option<Secret>,
+    pub token: Option<Secret>,
+    pub user: Option<String>,
+    pub password: Option<Secret>,
+}
+
+/// Same defaults as the `--reconnect-*` flags
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectConfig {
+    pub attempts: usize,
+    pub delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub jitter_ms: u64,
+    pub buffer_size: usize,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        let reconnect = Reconnect::default();
+        Self {
+            attempts: reconnect.attempts,
+            delay_ms: reconnect.delay.as_millis() as u64,
+            max_delay_ms: reconnect.max_delay.as_millis() as u64,
+            jitter_ms: reconnect.jitter.as_millis() as u64,
+            buffer_size: reconnect.buffer_size,
+        }
+    }
+}
+
+impl From<ReconnectConfig> for Reconnect {
+    fn from(config: ReconnectConfig) -> Self {
+        Self {
+            attempts: config.attempts,
+            delay: Duration::from_millis(config.delay_ms),
+            max_delay: Duration::from_millis(config.max_delay_ms),
+            jitter: Duration::from_millis(config.jitter_ms),
+            buffer_size: config.buffer_size,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JetStreamSourceConfig {
+    pub stream: String,
+    #[serde(default = "default_durable")]
+    pub durable: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JetStreamTargetConfig {
+    #[serde(default = "default_target_max_in_flight")]
+    pub max_in_flight: usize,
+    #[serde(default = "default_ack_timeout_ms")]
+    pub ack_timeout_ms: u64,
+    #[serde(default = "default_retries")]
+    pub retries: usize,
+}
+
+fn default_max_in_flight() -> usize {
+    1024
+}
+
+fn default_spill_max_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_spill_segment_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_durable() -> String {
+    "naps".to_string()
+}
+
+fn default_target_max_in_flight() -> usize {
+    256
+}
+
+fn default_ack_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_retries() -> usize {
+    3
+}
+
+fn default_script_timeout_ms() -> u64 {
+    30000
+}
+
+fn default_script_concurrency() -> usize {
+    1
+}
+
+fn default_request_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_request_workers() -> usize {
+    16
+}
+
+impl Config {
+    /// Loads the file, picking the format from its extension
+    pub fn load(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default();
+
+        match extension {
+            "yaml" | "yml" => Self::from_yaml(&content),
+            "toml" => Self::from_toml(&content),
+            ext => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unsupported config format '{}', use yaml or toml", ext),
+            )),
+        }
+    }
+
+    pub fn from_yaml(content: &str) -> Result<Self> {
+        serde_yaml::from_str(content).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self> {
+        toml::from_str(content).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Resolves secrets and turns every route into what the loops need to run it
+    pub fn into_routes(self) -> Result<Vec<Route>> {
+        let mut names: Vec<&str> = self.routes.iter().map(|r| r.name.as_str()).collect();
+        names.sort_unstable();
+        if let Some(dup) = names.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("route '{}' is defined more than once", dup[0]),
+            ));
+        }
+
+        self.routes
+            .into_iter()
+            .map(RouteConfig::into_route)
+            .collect()
+    }
+}
+
+impl RouteConfig {
+    fn into_route(self) -> Result<Route> {
+        if self.script.is_some() && self.script_file.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("route '{}' has both a script and a script file", self.name),
+            ));
+        }
+
+        let route = Route {
+            source: connect_options(
+                self.source.url,
+                self.source.tls,
+                &self.source.auth,
+                self.source.reconnect,
+            )?,
+            target: connect_options(
+                self.destination.url,
+                self.destination.tls,
+                &self.destination.auth,
+                self.destination.reconnect,
+            )?,
+            topics: self.subjects,
+            reverse_topics: self.reverse_subjects,
+            mappings: Mappings(
+                self.mappings
+                    .iter()
+                    .map(|m| SubjectMapping::new(&m.from, &m.to))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            source_jetstream: self.source.jetstream.map(|js| JetStreamSource {
+                stream: js.stream,
+                durable: js.durable,
+            }),
+            target_jetstream: self.destination.jetstream.map(|js| JetStreamTarget {
+                max_in_flight: js.max_in_flight,
+                ack_timeout: Duration::from_millis(js.ack_timeout_ms),
+                retries: js.retries,
+            }),
+            max_in_flight: self.max_in_flight,
+            spill: self.spill.map(|spill| SpillOptions {
+                dir: spill.dir.into(),
+                max_bytes: spill.max_bytes,
+                segment_bytes: spill.segment_bytes,
+            }),
+            request_reply: self.request_reply.map(|rr| RequestReply {
+                timeout: Duration::from_millis(rr.timeout_ms),
+                workers: rr.workers,
+            }),
+            script: self.script.unwrap_or_default(),
+            script_file: self.script_file,
+            script_timeout: Duration::from_millis(self.script_timeout_ms),
+            script_concurrency: self.script_concurrency,
+            dead_letter: self.dead_letter.map(DeadLetterTarget::from),
+            name: self.name,
+        };
+        route.validate()?;
+
+        Ok(route)
+    }
+}
+
+fn connect_options(
+    url: String,
+    tls: TlsConfig,
+    auth: &AuthConfig,
+    reconnect: ReconnectConfig,
+) -> Result<ConnectOptions> {
+    let secret = |secret: &Option<Secret>| secret.as_ref().map(Secret::resolve).transpose();
+
+    // Same precedence as the command line flags
+    let auth = if let Some(creds) = &auth.creds {
+        Auth::Credentials(creds.clone())
+    } else if let Some(seed) = secret(&auth.nkey_seed)? {
+        Auth::NKey(seed)
+    } else if let Some(token) = secret(&auth.token)? {
+        Auth::Token(token)
+    } else if let Some(user) = &auth.user {
+        Auth::UserPassword {
+            user: user.clone(),
+            password: secret(&auth.password)?.unwrap_or_default(),
+        }
+    } else {
+        Auth::None
+    };
+
+    Ok(ConnectOptions {
+        url,
+        tls: TlsOptions {
+            ca: tls.ca,
+            cert: tls.cert,
+            key: tls.key,
+            required: tls.required,
+            server_name: tls.server_name,
+        },
+        auth,
+        reconnect: reconnect.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use crate::conn::Auth;
+    use crate::dead_letter::DeadLetterTarget;
+    use std::time::Duration;
+
+    #[test]
+    fn yaml_routes() {
+        let config = Config::from_yaml(
+            r#"
+routes:
+  - name: orders
+    source:
+      url: nats://aws:4222
+      auth:
+        token: { value: secret }
+      jetstream:
+        stream: ORDERS
+    destination:
+      url: tls://aks:4222
+      tls:
+        ca: ./ca.pem
+      reconnect:
+        attempts: 10
+        max_delay_ms: 2000
+      jetstream:
+        retries: 5
+    subjects: ["orders.>"]
+    mappings:
+      - { from: "orders.>", to: "mirror.orders.>" }
+  - name: users
+    source:
+      url: nats://aws:4222
+    destination:
+      url: nats://aks:4222
+    subjects: ["users.created", "users.deleted"]
+    reverse_subjects: ["users.synced"]
+    spill:
+      dir: /var/lib/naps/users
+    script: "async function recv() { return true; }"
+    script_concurrency: 8
+    dead_letter:
+      destination: naps.dead.users
+  - name: pricing
+    source:
+      url: nats://aws:4222
+    destination:
+      url: nats://aks:4222
+    subjects: ["pricing.quote"]
+    request_reply:
+      timeout_ms: 250
+"#,
+        )
+        .unwrap();
+
+        let routes = config.into_routes().unwrap();
+        assert_eq!(routes.len(), 3);
+
+        let orders = &routes[0];
+        assert_eq!(orders.name, "orders");
+        assert!(matches!(&orders.source.auth, Auth::Token(t) if t == "secret"));
+        assert_eq!(orders.source_jetstream.as_ref().unwrap().durable, "naps");
+        assert_eq!(orders.target.tls.ca.as_deref(), Some("./ca.pem"));
+        assert_eq!(orders.target.reconnect.attempts, 10);
+        assert_eq!(orders.target.reconnect.max_delay, Duration::from_secs(2));
+        assert_eq!(orders.source.reconnect.attempts, 0);
+        let target_js = orders.target_jetstream.as_ref().unwrap();
+        assert_eq!(target_js.retries, 5);
+        assert_eq!(target_js.ack_timeout, Duration::from_millis(5000));
+        assert!(!orders.has_script());
+        assert_eq!(
+            orders.mappings.map("orders.created"),
+            Some("mirror.orders.created".to_string())
+        );
+
+        let users = &routes[1];
+        assert_eq!(users.topics, vec!["users.created", "users.deleted"]);
+        assert_eq!(users.reverse_topics, vec!["users.synced"]);
+        assert_eq!(users.max_in_flight, 1024);
+        let spill = users.spill.as_ref().unwrap();
+        assert_eq!(spill.dir.to_str(), Some("/var/lib/naps/users"));
+        assert_eq!(spill.max_bytes, 1024 * 1024 * 1024);
+        assert!(orders.spill.is_none());
+        assert!(users.has_script());
+        assert_eq!(users.script_timeout, Duration::from_secs(30));
+        assert_eq!(users.script_concurrency, 8);
+        assert!(users.request_reply.is_none());
+        assert_eq!(
+            users.dead_letter,
+            Some(DeadLetterTarget::Destination("naps.dead.users".to_string()))
+        );
+        assert!(orders.dead_letter.is_none());
+
+        let pricing = routes[2].request_reply.as_ref().unwrap();
+        assert_eq!(pricing.timeout, Duration::from_millis(250));
+        assert_eq!(pricing.workers, 16);
+    }
+
+    #[test]
+    fn toml_routes() {
+        let config = Config::from_toml(
+            r#"
+[[routes]]
+name = "orders"
+subjects = ["orders.>"]
+max_in_flight = 10
+
+[routes.source]
+url = "nats://aws:4222"
+auth = { user = "naps", password = { env = "NAPS_TEST_UNSET_PASSWORD" } }
+
+[routes.destination]
+url = "nats://aks:4222"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.routes[0].max_in_flight, 10);
+        // Missing env secrets are reported instead of silently ignored
+        assert!(config.into_routes().is_err());
+    }
+
+    #[test]
+    fn duplicated_route_names() {
+        let config = Config::from_yaml(
+            r#"
+routes:
+  - name: orders
+    source: { url: "nats://a:4222" }
+    destination: { url: "nats://b:4222" }
+    subjects: ["a"]
+  - name: orders
+    source: { url: "nats://a:4222" }
+    destination: { url: "nats://b:4222" }
+    subjects: ["b"]
+"#,
+        )
+        .unwrap();
+
+        assert!(config.into_routes().is_err());
+    }
+
+    #[test]
+    fn no_jetstream_in_flight() {
+        let config = Config::from_yaml(
+            r#"
+routes:
+  - name: orders
+    source: { url: "nats://a:4222" }
+    destination:
+      url: nats://b:4222
+      jetstream:
+        max_in_flight: 0
+    subjects: ["orders.>"]
+"#,
+        )
+        .unwrap();
+        assert!(config.into_routes().is_err());
+    }
+
+    #[test]
+    fn request_reply_conflicts() {
+        for setting in [
+            "script: \"function recv() { return true; }\"",
+            "script_file: ./scripts/orders.ts",
+            "reverse_subjects: [\"orders.synced\"]",
+            "dead_letter: { file: ./dead.jsonl }",
+        ] {
+            let config = Config::from_yaml(&format!(
+                r#"
+routes:
+  - name: orders
+    source: {{ url: "nats://a:4222" }}
+    destination: {{ url: "nats://b:4222" }}
+    subjects: ["orders.>"]
+    request_reply: {{}}
+    {}
+"#,
+                setting
+            ))
+            .unwrap();
+            assert!(config.into_routes().is_err(), "{}", setting);
+        }
+
+        let config = Config::from_yaml(
+            r#"
+routes:
+  - name: orders
+    source:
+      url: nats://a:4222
+      jetstream:
+        stream: ORDERS
+    destination: { url: "nats://b:4222" }
+    subjects: ["orders.>"]
+    request_reply: {}
+"#,
+        )
+        .unwrap();
+        assert!(config.into_routes().is_err());
+    }
+
+    #[test]
+    fn no_request_workers() {
+        let config = Config::from_yaml(
+            r#"
+routes:
+  - name: orders
+    source: { url: "nats://a:4222" }
+    destination: { url: "nats://b:4222" }
+    subjects: ["orders.>"]
+    request_reply:
+      workers: 0
+"#,
+        )
+        .unwrap();
+        assert!(config.into_routes().is_err());
+    }
+
+    #[test]
+    fn tls_key_without_cert() {
+        let config = Config::from_yaml(
+            r#"
+routes:
+  - name: orders
+    source:
+      url: nats://a:4222
+      tls:
+        key: ./certs/client-key.pem
+    destination: { url: "nats://b:4222" }
+    subjects: ["orders.>"]
+"#,
+        )
+        .unwrap();
+        assert!(config.into_routes().is_err());
+    }
+
+    #[test]
+    fn dead_letters_read_back() {
+        let config = Config::from_yaml(
+            r#"
+routes:
+  - name: orders
+    source: { url: "nats://a:4222" }
+    destination: { url: "nats://b:4222" }
+    subjects: ["orders.>"]
+    dead_letter:
+      source: orders.dead
+"#,
+        )
+        .unwrap();
+        assert!(config.into_routes().is_err());
+    }
+
+    #[test]
+    fn script_files() {
+        let config = Config::from_yaml(
+            r#"
+routes:
+  - name: orders
+    source: { url: "nats://a:4222" }
+    destination: { url: "nats://b:4222" }
+    subjects: ["orders.>"]
+    script_file: ./scripts/orders.ts
+"#,
+        )
+        .unwrap();
+        let routes = config.into_routes().unwrap();
+        assert_eq!(
+            routes[0].script_file.as_deref(),
+            Some("./scripts/orders.ts")
+        );
+        assert!(routes[0].has_script());
+
+        let config = Config::from_yaml(
+            r#"
+routes:
+  - name: orders
+    source: { url: "nats://a:4222" }
+    destination: { url: "nats://b:4222" }
+    subjects: ["orders.>"]
+    script: "function recv() { return true; }"
+    script_file: https://example.com/orders.ts
+"#,
+        )
+        .unwrap();
+        assert!(config.into_routes().is_err());
+    }
+}
@@ -0,0 +1,367 @@
+use crate::error;
+use crate::metrics::RouteMetrics;
+use crate::stats::Event;
+use crossbeam::channel::Sender;
+use nats::Connection;
+use nkeys::KeyPair;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName};
+use std::convert::TryFrom;
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// TLS settings for one side of the relay.
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// PEM bundle with the CAs used to verify the server certificate
+    pub ca: Option<String>,
+    /// PEM client certificate, for mTLS
+    pub cert: Option<String>,
+    /// PEM client private key, for mTLS
+    pub key: Option<String>,
+    /// Refuse to talk to servers that do not offer TLS
+    pub required: bool,
+    /// Name to verify the server certificate against instead of the URL host
+    pub server_name: Option<String>,
+}
+
+impl TlsOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.required
+            || self.ca.is_some()
+            || self.cert.is_some()
+            || self.key.is_some()
+            || self.server_name.is_some()
+    }
+}
+
+/// Credentials presented to one side of the relay.
+#[derive(Clone)]
+pub enum Auth {
+    None,
+    Token(String),
+    UserPassword {
+        user: String,
+        password: String,
+    },
+    /// NKey seed, the public key is derived from it
+    NKey(String),
+    /// Path to a JWT `.creds` file
+    Credentials(String),
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Auth::None
+    }
+}
+
+impl Debug for Auth {
+    /// Secrets are never printed
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Auth::None => write!(f, "None"),
+            Auth::Token(_) => write!(f, "Token(***)"),
+            Auth::UserPassword { user, .. } => write!(f, "UserPassword({}, ***)", user),
+            Auth::NKey(_) => write!(f, "NKey(***)"),
+            Auth::Credentials(path) => write!(f, "Credentials({})", path),
+        }
+    }
+}
+
+impl Auth {
+    /// Options carrying the credentials, the nats client only builds them from scratch
+    fn options(&self) -> Result<nats::Options> {
+        let opts = match self {
+            Auth::None => nats::Options::new(),
+            Auth::Token(token) => nats::Options::with_token(token),
+            Auth::UserPassword { user, password } => nats::Options::with_user_pass(user, password),
+            Auth::NKey(seed) => {
+                let kp = KeyPair::from_seed(seed)
+                    .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+                // Fail now rather than on every connection attempt
+                kp.sign(b"naps")
+                    .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+                let public_key = kp.public_key();
+                let kp = Arc::new(kp);
+                nats::Options::with_nkey(public_key.as_str(), move |nonce| {
+                    kp.sign(nonce).unwrap_or_else(|e| {
+                        // An empty signature makes the server refuse the connection
+                        error!(kind = "auth"; "cannot sign the server nonce: {}", e);
+                        vec![]
+                    })
+                })
+            }
+            Auth::Credentials(path) => nats::Options::with_credentials(path),
+        };
+
+        Ok(opts)
+    }
+}
+
+/// Reads a secret from a file, dropping the trailing newline editors tend to leave.
+pub fn read_secret(path: &str) -> Result<String> {
+    let secret = std::fs::read_to_string(path)?;
+    Ok(secret.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// Which end of a route a connection talks to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Source,
+    Destination,
+}
+
+impl Display for Side {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Side::Source => write!(f, "source"),
+            Side::Destination => write!(f, "destination"),
+        }
+    }
+}
+
+/// How a side reconnects once its server goes away.
+///
+/// # Example
+///
+/// ```rust
+/// use naps::conn::Reconnect;
+/// use std::time::Duration;
+///
+/// let reconnect = Reconnect {
+///     delay: Duration::from_millis(100),
+///     max_delay: Duration::from_secs(1),
+///     ..Default::default()
+/// };
+/// assert_eq!(reconnect.backoff(3), Duration::from_millis(400));
+/// assert_eq!(reconnect.backoff(10), Duration::from_secs(1));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Reconnect {
+    /// Attempts before giving up on the server, zero retries forever
+    pub attempts: usize,
+    /// Wait before the first attempt, doubled after every failed one
+    pub delay: Duration,
+    pub max_delay: Duration,
+    /// Up to this much is added to every wait, so clients do not all come back at once
+    pub jitter: Duration,
+    /// Bytes published while disconnected, sent once the connection is back
+    pub buffer_size: usize,
+}
+
+impl Reconnect {
+    /// Wait before the given attempt, starting at 1, jitter aside
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31) as u32;
+        self.delay
+            .saturating_mul(1 << doublings)
+            .min(self.max_delay)
+    }
+
+    fn apply(&self, opts: nats::Options) -> nats::Options {
+        let reconnect = self.clone();
+        let max_reconnects = match self.attempts {
+            0 => None,
+            attempts => Some(attempts),
+        };
+
+        opts.max_reconnects(max_reconnects)
+            .reconnect_buffer_size(self.buffer_size)
+            .reconnect_delay_callback(move |attempt| {
+                reconnect.backoff(attempt) + reconnect.jitter.mul_f64(rand::random::<f64>())
+            })
+            // Servers still starting up get the same treatment as the ones that went away
+            .retry_on_failed_connect()
+    }
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self {
+            attempts: 0,
+            delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            jitter: Duration::from_millis(100),
+            buffer_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// Everything needed to open a connection to one NATS cluster.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    pub url: String,
+    pub tls: TlsOptions,
+    pub auth: Auth,
+    pub reconnect: Reconnect,
+}
+
+impl ConnectOptions {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            ..Default::default()
+        }
+    }
+
+    /// Connects, reporting every disconnection and reconnection of `side` to the stats loop
+    /// and to the health checks
+    pub fn connect(
+        &self,
+        side: Side,
+        stats_sc: &Sender<Event>,
+        metrics: &RouteMetrics,
+    ) -> Result<Connection> {
+        let disconnected = stats_sc.clone();
+        let reconnected = stats_sc.clone();
+        let metrics_disconnected = metrics.clone();
+        let metrics_reconnected = metrics.clone();
+
+        metrics.connection(side, false);
+        let nc = self
+            .to_nats_options()?
+            .disconnect_callback(move || {
+                metrics_disconnected.connection(side, false);
+                let _ = disconnected.send(Event::Disconnected(side));
+            })
+            .reconnect_callback(move || {
+                metrics_reconnected.connection(side, true);
+                let _ = reconnected.send(Event::Reconnected(side));
+            })
+            .connect(self.url.as_str())?;
+        metrics.connection(side, true);
+
+        Ok(nc)
+    }
+
+    fn to_nats_options(&self) -> Result<nats::Options> {
+        let mut opts = self.reconnect.apply(self.auth.options()?);
+        let tls = &self.tls;
+
+        if !tls.is_enabled() {
+            return Ok(opts);
+        }
+
+        opts = opts.tls_required(true);
+
+        if let Some(server_name) = &tls.server_name {
+            // nats does not expose a way to override the name checked against the server
+            // certificate, so the whole rustls config is built here instead.
+            return Ok(opts.tls_client_config(client_config(tls, server_name)?));
+        }
+
+        if let Some(ca) = &tls.ca {
+            opts = opts.add_root_certificate(ca);
+        }
+
+        match (&tls.cert, &tls.key) {
+            (Some(cert), Some(key)) => opts = opts.client_cert(cert, key),
+            (None, None) => {}
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "tls client certificate and key must be given together",
+                ))
+            }
+        }
+
+        Ok(opts)
+    }
+}
+
+/// Verifies server certificates against a fixed name rather than the host being dialed.
+struct ServerNameOverride {
+    inner: WebPkiVerifier,
+    server_name: ServerName,
+}
+
+impl ServerCertVerifier for ServerNameOverride {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            &self.server_name,
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
+
+fn client_config(tls: &TlsOptions, server_name: &str) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match &tls.ca {
+        Some(ca) => {
+            for cert in load_certs(ca)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            }
+        }
+        None => {
+            for cert in rustls_native_certs::load_native_certs()? {
+                let _ = roots.add(&Certificate(cert.0));
+            }
+        }
+    }
+
+    let server_name = ServerName::try_from(server_name)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+    let verifier = ServerNameOverride {
+        inner: WebPkiVerifier::new(roots, None),
+        server_name,
+    };
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+
+    let config = match (&tls.cert, &tls.key) {
+        (Some(cert), Some(key)) => builder
+            .with_single_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "tls client certificate and key must be given together",
+            ))
+        }
+    };
+
+    Ok(config)
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::RSAKey(key)) => return Ok(PrivateKey(key)),
+            Some(rustls_pemfile::Item::PKCS8Key(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => break,
+        }
+    }
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        format!("no private key found in {}", path),
+    ))
+}
@@ -0,0 +1,117 @@
+use crate::log;
+use crate::stats::SubjectTable;
+use crossterm::{
+    cursor, execute, queue,
+    style::{self, Color, Print, PrintStyledContent, Stylize},
+    terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use std::io::{self, Result, Stderr, Write};
+
+/// Lines taken by the header, the table titles, the queues title and the notice
+const FIXED_LINES: usize = 6;
+
+/// Full-screen view of the busiest subjects and queues, drawn on stderr in place of the
+/// progress line. Logs are suspended while it is open, the regular screen and logs are
+/// restored on drop.
+pub struct Dashboard {
+    stderr: Stderr,
+}
+
+impl Dashboard {
+    pub fn open() -> Result<Self> {
+        let mut stderr = io::stderr();
+        execute!(stderr, EnterAlternateScreen, cursor::Hide)?;
+        log::suspend(true);
+
+        Ok(Self { stderr })
+    }
+
+    /// `queues` holds the route, name and depth of every queue
+    pub fn draw(
+        &mut self,
+        header: &str,
+        subjects: &SubjectTable,
+        queues: &[(String, String, usize)],
+        notice: &str,
+    ) -> Result<()> {
+        let (cols, rows) = terminal::size().unwrap_or((80, 24));
+        let width = cols as usize;
+        let room = (rows as usize).saturating_sub(FIXED_LINES + queues.len());
+
+        queue!(
+            self.stderr,
+            cursor::MoveTo(0, 0),
+            Clear(ClearType::All),
+            PrintStyledContent(style::style(header).with(Color::Green)),
+            cursor::MoveToNextLine(2),
+            PrintStyledContent(
+                style::style(row(width, "SUBJECT", "MSG/S", "IN", "OUT", "ERRORS")).bold()
+            ),
+            cursor::MoveToNextLine(1),
+        )?;
+
+        for (subject, stats) in subjects.top(room) {
+            let rate = format!("{:.1}", stats.rate);
+            let line = row(
+                width,
+                subject,
+                &rate,
+                &stats.received.to_string(),
+                &stats.published.to_string(),
+                &stats.failed.to_string(),
+            );
+            if stats.failed > 0 {
+                queue!(
+                    self.stderr,
+                    PrintStyledContent(style::style(line).with(Color::Yellow))
+                )?;
+            } else {
+                queue!(self.stderr, Print(line))?;
+            }
+            queue!(self.stderr, cursor::MoveToNextLine(1))?;
+        }
+
+        queue!(
+            self.stderr,
+            cursor::MoveToNextLine(1),
+            PrintStyledContent(style::style("QUEUE                          DEPTH").bold()),
+            cursor::MoveToNextLine(1),
+        )?;
+        for (route, name, depth) in queues {
+            let queue = format!("{}/{}", route, name);
+            queue!(
+                self.stderr,
+                Print(format!("{:<30} {:>6}", truncate(&queue, 30), depth)),
+                cursor::MoveToNextLine(1),
+            )?;
+        }
+
+        queue!(self.stderr, Print(truncate(notice, width)))?;
+        self.stderr.flush()
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        let _ = execute!(self.stderr, cursor::Show, LeaveAlternateScreen);
+        log::suspend(false);
+    }
+}
+
+/// Subject column takes whatever the numbers leave
+fn row(width: usize, subject: &str, rate: &str, received: &str, out: &str, errors: &str) -> String {
+    let subject_width = width.saturating_sub(44).max(10);
+    format!(
+        "{:<sw$} {:>10} {:>10} {:>10} {:>10}",
+        truncate(subject, subject_width),
+        rate,
+        received,
+        out,
+        errors,
+        sw = subject_width
+    )
+}
+
+fn truncate(text: &str, width: usize) -> String {
+    text.chars().take(width).collect()
+}
@@ -0,0 +1,297 @@
+use crate::log::timestamp;
+use crate::metrics::RouteMetrics;
+use crate::msg::Msg;
+use crate::shutdown::Drain;
+use crate::{debug, warn};
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
+use nats::Connection;
+use serde_json::{json, Map, Value};
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// Why the message was dead-lettered
+pub const REASON_HEADER: &str = "Naps-Dead-Letter-Reason";
+/// Which stage gave up on it, `script` or `publish`
+pub const STAGE_HEADER: &str = "Naps-Dead-Letter-Stage";
+/// When, in RFC 3339
+pub const TIME_HEADER: &str = "Naps-Dead-Letter-Time";
+pub const ROUTE_HEADER: &str = "Naps-Dead-Letter-Route";
+/// Subject the message had when it failed
+pub const SUBJECT_HEADER: &str = "Naps-Dead-Letter-Subject";
+/// Subject it was read from, when a mapping renamed it before it failed to publish
+pub const ORIGINAL_SUBJECT_HEADER: &str = "Naps-Dead-Letter-Original-Subject";
+
+/// How long to wait for failed messages before checking for an expired drain
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where the messages a route gives up on are kept for inspection and replay.
+///
+/// # Example
+///
+/// ```rust
+/// use naps::dead_letter::DeadLetterTarget;
+///
+/// let target: DeadLetterTarget = "destination:naps.dead.orders".parse().unwrap();
+/// assert_eq!(target, DeadLetterTarget::Destination("naps.dead.orders".to_string()));
+/// assert!("elsewhere:naps.dead".parse::<DeadLetterTarget>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeadLetterTarget {
+    /// A subject on the source cluster
+    Source(String),
+    /// A subject on the destination cluster
+    Destination(String),
+    /// A local file, one JSON object per message
+    File(PathBuf),
+}
+
+impl FromStr for DeadLetterTarget {
+    type Err = Error;
+
+    /// `source:<subject>`, `destination:<subject>` or `file:<path>`
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("source", subject)) if !subject.is_empty() => Ok(Self::Source(subject.into())),
+            Some(("destination", subject)) if !subject.is_empty() => {
+                Ok(Self::Destination(subject.into()))
+            }
+            Some(("file", path)) if !path.is_empty() => Ok(Self::File(path.into())),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "invalid dead letter '{}', use source:<subject>, destination:<subject> or \
+                     file:<path>",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+/// Stage of the pipeline a message failed at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    /// The script threw
+    Script,
+    /// The destination did not take it
+    Publish,
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stage::Script => write!(f, "script"),
+            Stage::Publish => write!(f, "publish"),
+        }
+    }
+}
+
+/// Hands failed messages over to the dead-letter loop of a route. Routes without a dead-letter
+/// destination use the default, which drops them, leaving the source to redeliver them if it
+/// can.
+#[derive(Debug, Clone, Default)]
+pub struct DeadLetters {
+    route: String,
+    dead_letter_sc: Option<Sender<Msg>>,
+}
+
+impl DeadLetters {
+    pub fn new(route: &str, dead_letter_sc: Sender<Msg>) -> Self {
+        Self {
+            route: route.to_string(),
+            dead_letter_sc: Some(dead_letter_sc),
+        }
+    }
+
+    /// Adds the failure headers to the message, as it was when it failed, and sends it on. It
+    /// is acked once stored.
+    pub fn send(&self, mut msg: Msg, stage: Stage, reason: &str) {
+        let dead_letter_sc = match &self.dead_letter_sc {
+            Some(dead_letter_sc) => dead_letter_sc,
+            // Nacked on drop
+            None => return,
+        };
+
+        msg.headers.insert(REASON_HEADER, reason);
+        msg.headers.insert(STAGE_HEADER, &stage.to_string());
+        msg.headers
+            .insert(TIME_HEADER, &timestamp(SystemTime::now()));
+        msg.headers.insert(ROUTE_HEADER, &self.route);
+        msg.headers.insert(SUBJECT_HEADER, &msg.topic.clone());
+        let _ = dead_letter_sc.send(msg);
+    }
+}
+
+/// Stores dead letters where the route says.
+pub enum DeadLetterWriter {
+    Nats { nc: Connection, subject: String },
+    File(File),
+}
+
+impl DeadLetterWriter {
+    /// Subjects are published to on one of the connections of the route, `(source, target)`
+    pub fn open(
+        target: &DeadLetterTarget,
+        (source_nc, target_nc): (&Connection, &Connection),
+    ) -> Result<Self> {
+        Ok(match target {
+            DeadLetterTarget::Source(subject) => Self::Nats {
+                nc: source_nc.clone(),
+                subject: subject.clone(),
+            },
+            DeadLetterTarget::Destination(subject) => Self::Nats {
+                nc: target_nc.clone(),
+                subject: subject.clone(),
+            },
+            DeadLetterTarget::File(path) => {
+                Self::File(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+        })
+    }
+
+    /// Returns once the message is safely stored: flushed to the server or to the file
+    fn store(&mut self, msg: &Msg) -> Result<()> {
+        match self {
+            Self::Nats { nc, subject } => {
+                let headers = msg.headers.to_nats();
+                nc.publish_with_reply_or_headers(subject, None, headers.as_ref(), &msg.data)?;
+                nc.flush()
+            }
+            Self::File(file) => {
+                writeln!(file, "{}", to_json(msg))?;
+                file.flush()?;
+                file.sync_data()
+            }
+        }
+    }
+}
+
+/// One line of a dead-letter file. The payload is base64 encoded, as it may be binary.
+fn to_json(msg: &Msg) -> Value {
+    let mut headers = Map::new();
+    for (name, values) in msg.headers.iter() {
+        headers.insert(name.clone(), json!(values));
+    }
+    json!({
+        "subject": msg.topic,
+        "headers": headers,
+        "data": base64::encode(&msg.data),
+    })
+}
+
+/// Stores every dead letter until the senders are gone, i.e. the loops of the route exited, or
+/// the drain expires.
+pub fn dead_letter_loop(
+    mut writer: DeadLetterWriter,
+    dead_letter_rc: &Receiver<Msg>,
+    metrics: RouteMetrics,
+    drain: Drain,
+) -> Result<()> {
+    while !drain.is_expired() {
+        let mut msg = match dead_letter_rc.recv_timeout(POLL_INTERVAL) {
+            Ok(msg) => msg,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        match writer.store(&msg) {
+            Ok(()) => {
+                metrics.dead_lettered(&msg.topic);
+                msg.ack();
+            }
+            // Nacked on drop
+            Err(e) => warn!(
+                route = msg.headers.get(ROUTE_HEADER).unwrap_or_default(),
+                subject = msg,
+                kind = e.kind();
+                "cannot store dead letter: {}",
+                e
+            ),
+        }
+    }
+
+    debug!("dead letter loop exited");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        dead_letter_loop, DeadLetterTarget, DeadLetterWriter, DeadLetters, Stage, REASON_HEADER,
+        STAGE_HEADER, SUBJECT_HEADER,
+    };
+    use crate::metrics::Metrics;
+    use crate::msg::Msg;
+    use crate::shutdown::Drain;
+    use crossbeam::channel::bounded;
+    use serde_json::Value;
+    use std::fs::{File, OpenOptions};
+    use std::io::{BufRead, BufReader};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    #[test]
+    fn targets() {
+        assert_eq!(
+            "source:dead.orders".parse::<DeadLetterTarget>().unwrap(),
+            DeadLetterTarget::Source("dead.orders".to_string())
+        );
+        assert_eq!(
+            "file:/var/lib/naps/dead.jsonl"
+                .parse::<DeadLetterTarget>()
+                .unwrap(),
+            DeadLetterTarget::File("/var/lib/naps/dead.jsonl".into())
+        );
+        assert!("destination:".parse::<DeadLetterTarget>().is_err());
+        assert!("dead.orders".parse::<DeadLetterTarget>().is_err());
+    }
+
+    #[test]
+    fn stored_in_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead.jsonl");
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let metrics = Metrics::default();
+        let (dead_letter_sc, dead_letter_rc) = bounded(8);
+        let dead_letters = DeadLetters::new("orders", dead_letter_sc);
+
+        dead_letters.send(
+            Msg::new(vec![0, 159, 146, 150], "orders.created".to_string()),
+            Stage::Script,
+            "TypeError: boom",
+        );
+        // Without a destination, failed messages are dropped
+        DeadLetters::default().send(
+            Msg::new(vec![], "orders.deleted".to_string()),
+            Stage::Publish,
+            "timeout",
+        );
+        drop(dead_letters);
+
+        let drain = Drain::new(Arc::new(AtomicBool::new(false)));
+        let writer = DeadLetterWriter::File(file);
+        dead_letter_loop(writer, &dead_letter_rc, metrics.route("orders"), drain).unwrap();
+
+        let lines: Vec<String> = BufReader::new(File::open(&path).unwrap())
+            .lines()
+            .map(|line| line.unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        let letter: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(letter["subject"], "orders.created");
+        assert_eq!(letter["data"], "AJ+Slg==");
+        assert_eq!(letter["headers"][REASON_HEADER][0], "TypeError: boom");
+        assert_eq!(letter["headers"][STAGE_HEADER][0], "script");
+        assert_eq!(letter["headers"][SUBJECT_HEADER][0], "orders.created");
+        assert_eq!(metrics.counters("orders", "orders.created").dead_letters, 1);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::metrics::Metrics;
+use std::fmt::Write;
+
+/// Where the script of a route stands.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptStatus {
+    /// Its module is being evaluated, no message is processed yet
+    Loading,
+    Loaded,
+    /// Messages of the route are not processed at all
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Ok,
+    /// Likely to recover by itself, like a reconnecting side
+    NotReady,
+    /// Only a restart helps
+    Failing,
+}
+
+#[derive(Debug)]
+struct Check {
+    state: State,
+    name: String,
+    detail: String,
+}
+
+/// What `/healthz` and `/readyz` answer, built from the state the loops record in [`Metrics`].
+///
+/// # Example
+///
+/// ```rust
+/// use naps::conn::Side;
+/// use naps::health::Health;
+/// use naps::metrics::Metrics;
+///
+/// let metrics = Metrics::default();
+/// let orders = metrics.route("orders");
+/// orders.connection(Side::Source, true);
+/// orders.connection(Side::Destination, false);
+///
+/// let health = Health::check(&metrics, 1000);
+/// assert!(health.is_live());
+/// assert!(!health.is_ready());
+/// ```
+#[derive(Debug)]
+pub struct Health {
+    checks: Vec<Check>,
+}
+
+impl Health {
+    /// Queues holding more than `backlog` messages make the process not ready
+    pub fn check(metrics: &Metrics, backlog: usize) -> Self {
+        let mut checks = vec![];
+
+        for (route, error) in metrics.stopped() {
+            checks.push(Check {
+                state: State::Failing,
+                name: route,
+                detail: format!("stopped: {}", error),
+            });
+        }
+
+        for (route, side, up) in metrics.connections() {
+            let (state, detail) = if up {
+                (State::Ok, "connected")
+            } else {
+                (State::NotReady, "disconnected")
+            };
+            checks.push(Check {
+                state,
+                name: format!("{}/{}", route, side),
+                detail: detail.to_string(),
+            });
+        }
+
+        for (route, status) in metrics.scripts() {
+            let (state, detail) = match status {
+                ScriptStatus::Loading => (State::NotReady, "loading".to_string()),
+                ScriptStatus::Loaded => (State::Ok, "loaded".to_string()),
+                ScriptStatus::Failed(e) => (State::Failing, format!("failed: {}", e)),
+            };
+            checks.push(Check {
+                state,
+                name: format!("{}/script", route),
+                detail,
+            });
+        }
+
+        for (route, queue, depth) in metrics.queue_depths() {
+            let state = if depth > backlog {
+                State::NotReady
+            } else {
+                State::Ok
+            };
+            checks.push(Check {
+                state,
+                name: format!("{}/{}", route, queue),
+                detail: format!("{} queued, {} at most", depth, backlog),
+            });
+        }
+
+        Self { checks }
+    }
+
+    /// Nothing is broken beyond what the process recovers from by itself
+    pub fn is_live(&self) -> bool {
+        self.checks.iter().all(|c| c.state != State::Failing)
+    }
+
+    /// Every side is connected, every script loaded and no queue is backing up
+    pub fn is_ready(&self) -> bool {
+        self.checks.iter().all(|c| c.state == State::Ok)
+    }
+
+    /// One line per check
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for check in &self.checks {
+            let state = match check.state {
+                State::Ok => "ok",
+                State::NotReady => "not ready",
+                State::Failing => "failing",
+            };
+            let _ = writeln!(out, "{:<9} {}: {}", state, check.name, check.detail);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Health, ScriptStatus};
+    use crate::conn::Side;
+    use crate::metrics::Metrics;
+
+    #[test]
+    fn live_and_ready() {
+        let metrics = Metrics::default();
+        let orders = metrics.route("orders");
+        orders.connection(Side::Source, true);
+        orders.connection(Side::Destination, true);
+        orders.script(ScriptStatus::Loading);
+        orders.queue("write", || 10);
+
+        let health = Health::check(&metrics, 100);
+        assert!(health.is_live());
+        assert!(!health.is_ready());
+        assert!(health
+            .render()
+            .contains("not ready orders/script: loading\n"));
+
+        orders.script(ScriptStatus::Loaded);
+        assert!(Health::check(&metrics, 100).is_ready());
+        // Backing up is not fatal
+        let health = Health::check(&metrics, 5);
+        assert!(health.is_live());
+        assert!(!health.is_ready());
+        assert!(health
+            .render()
+            .contains("orders/write: 10 queued, 5 at most\n"));
+
+        metrics.route("users").stop("connection refused");
+        let health = Health::check(&metrics, 100);
+        assert!(!health.is_live());
+        assert!(health
+            .render()
+            .contains("failing   users: stopped: connection refused\n"));
+    }
+}
@@ -0,0 +1,187 @@
+use crate::health::Health;
+use crate::metrics::Metrics;
+use crate::{debug, warn};
+use std::io::{BufRead, BufReader, ErrorKind, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Content type of the Prometheus text format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Slow clients are given up on instead of blocking the next scrape
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: body.to_string(),
+        }
+    }
+
+    /// Every check is listed either way, the status tells whether the probe passed
+    fn probe(passed: bool, health: &Health) -> Self {
+        let status = if passed {
+            "200 OK"
+        } else {
+            "503 Service Unavailable"
+        };
+        Self::text(status, &health.render())
+    }
+}
+
+/// Serves `GET /metrics`, `/healthz` and `/readyz` until shutdown. Queues holding more than
+/// `backlog` messages fail the readiness check. Requests are answered one at a time, plenty for
+/// scrapers and probes.
+pub fn http_loop(
+    listener: TcpListener,
+    metrics: Metrics,
+    backlog: usize,
+    shutdown_arc: Arc<AtomicBool>,
+) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    let pause = Duration::from_millis(100);
+
+    while !shutdown_arc.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = handle(stream, &metrics, backlog) {
+                    warn!(kind = e.kind(); "http request failed: {}", e);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(pause),
+            Err(e) => warn!(kind = e.kind(); "cannot accept http connection: {}", e),
+        }
+    }
+
+    debug!("http loop exited");
+
+    Ok(())
+}
+
+fn handle(stream: TcpStream, metrics: &Metrics, backlog: usize) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Headers are of no use, but closing with unread data would reset the connection
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default();
+    let response = respond(method, path, metrics, backlog);
+
+    write!(
+        &stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )
+}
+
+fn respond(method: &str, path: &str, metrics: &Metrics, backlog: usize) -> Response {
+    match (method, path) {
+        ("GET", "/metrics") => Response {
+            status: "200 OK",
+            content_type: METRICS_CONTENT_TYPE,
+            body: metrics.render(),
+        },
+        ("GET", "/healthz") => {
+            let health = Health::check(metrics, backlog);
+            Response::probe(health.is_live(), &health)
+        }
+        ("GET", "/readyz") => {
+            let health = Health::check(metrics, backlog);
+            Response::probe(health.is_ready(), &health)
+        }
+        ("GET", _) => Response::text("404 Not Found", "not found\n"),
+        _ => Response::text("405 Method Not Allowed", "method not allowed\n"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::http_loop;
+    use crate::conn::Side;
+    use crate::metrics::Metrics;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn get(addr: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: naps\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_metrics() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let metrics = Metrics::default();
+        metrics.route("orders").received("orders.created", 42);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let shutdown_arc = Arc::clone(&shutdown);
+        let handle = thread::spawn(move || http_loop(listener, metrics, 1000, shutdown_arc));
+
+        let response = get(&addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(
+            response.contains(r#"naps_bytes_in_total{route="orders",subject="orders.created"} 42"#)
+        );
+
+        assert!(get(&addr, "/nope").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        shutdown.store(true, Ordering::Relaxed);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn serves_probes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let metrics = Metrics::default();
+        let orders = metrics.route("orders");
+        orders.connection(Side::Source, true);
+        orders.connection(Side::Destination, false);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let shutdown_arc = Arc::clone(&shutdown);
+        let handle = thread::spawn(move || http_loop(listener, metrics, 1000, shutdown_arc));
+
+        assert!(get(&addr, "/healthz").starts_with("HTTP/1.1 200 OK\r\n"));
+        let response = get(&addr, "/readyz");
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("not ready orders/destination: disconnected\n"));
+
+        orders.connection(Side::Destination, true);
+        assert!(get(&addr, "/readyz").starts_with("HTTP/1.1 200 OK\r\n"));
+
+        shutdown.store(true, Ordering::Relaxed);
+        handle.join().unwrap().unwrap();
+    }
+}
@@ -1,10 +1,28 @@
+pub mod ack;
 pub mod args;
+pub mod config;
+pub mod conn;
+pub mod dashboard;
+pub mod dead_letter;
+pub mod health;
+pub mod http;
+pub mod log;
+pub mod mapping;
+pub mod metrics;
 pub mod msg;
+pub mod origin;
 pub mod process;
 pub mod read;
+pub mod reload;
+pub mod reply;
+pub mod route;
+pub mod shutdown;
+pub mod spill;
 pub mod stats;
 pub mod timer;
 pub mod write;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::pin::Pin;
 
 use data_url::DataUrl;
@@ -18,7 +36,37 @@ use deno_core::ModuleSourceFuture;
 use deno_core::ModuleSpecifier;
 use deno_core::ModuleType;
 
-pub struct SimpleModuleLoader;
+/// Specifier of the module wrapping an inline script
+pub const MAIN_MODULE: &str = "naps:main";
+
+/// Loads modules from `http`, `https`, `file` and `data` URLs, as well as `naps:` modules
+/// generated by the processor, which are served from memory.
+#[derive(Default)]
+pub struct SimpleModuleLoader {
+    /// Code of the `naps:` modules, by specifier
+    modules: RefCell<HashMap<String, String>>,
+}
+
+impl SimpleModuleLoader {
+    /// Serves `code` as the module `specifier`, e.g. `naps:main`. Modules are evaluated once
+    /// per specifier, new code needs a new one.
+    pub fn insert(&self, specifier: &str, code: String) -> Result<ModuleSpecifier, Error> {
+        let specifier = ModuleSpecifier::parse(specifier)?;
+        if specifier.scheme() != "naps" {
+            bail!("In-memory modules use the naps scheme, not {}", specifier);
+        }
+        self.modules
+            .borrow_mut()
+            .insert(specifier.to_string(), code);
+
+        Ok(specifier)
+    }
+
+    /// Forgets the code of a module once it was loaded
+    pub fn remove(&self, specifier: &ModuleSpecifier) {
+        self.modules.borrow_mut().remove(specifier.as_str());
+    }
+}
 
 impl ModuleLoader for SimpleModuleLoader {
     fn resolve(
@@ -38,6 +86,7 @@ impl ModuleLoader for SimpleModuleLoader {
     ) -> Pin<Box<ModuleSourceFuture>> {
         let module_specifier = module_specifier.clone();
         let string_specifier = module_specifier.to_string();
+        let in_memory = self.modules.borrow().get(&string_specifier).cloned();
         async move {
             let bytes = match module_specifier.scheme() {
                 "http" | "https" => {
@@ -67,6 +116,10 @@ impl ModuleLoader for SimpleModuleLoader {
                     };
                     bytes.into()
                 }
+                "naps" => match in_memory {
+                    Some(code) => code.into_bytes().into(),
+                    None => bail!("Module not found {}", string_specifier),
+                },
                 schema => bail!("Invalid schema {}", schema),
             };
 
@@ -97,3 +150,29 @@ impl ModuleLoader for SimpleModuleLoader {
         .boxed_local()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{SimpleModuleLoader, MAIN_MODULE};
+    use deno_core::futures::executor::block_on;
+    use deno_core::ModuleLoader;
+
+    #[test]
+    fn serves_modules_from_memory() {
+        let loader = SimpleModuleLoader::default();
+        let main = loader
+            .insert(MAIN_MODULE, "export const answer: number = 42;".to_string())
+            .unwrap();
+
+        let source = block_on(loader.load(&main, None, false)).unwrap();
+        assert!(source.code.contains("answer = 42"));
+        assert_eq!(source.module_url_found, "naps:main");
+
+        loader.remove(&main);
+        assert!(block_on(loader.load(&main, None, false)).is_err());
+
+        let missing = "naps:main?version=1".parse().unwrap();
+        assert!(block_on(loader.load(&missing, None, false)).is_err());
+        assert!(loader.insert("file:///tmp/main.js", String::new()).is_err());
+    }
+}
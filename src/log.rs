@@ -0,0 +1,273 @@
+use serde_json::{Map, Value};
+use std::fmt::{Display, Formatter};
+use std::io::{self, ErrorKind, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static JSON: AtomicBool = AtomicBool::new(false);
+/// Set while the dashboard owns the terminal
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+/// How much gets logged, each level including the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl FromStr for Level {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown log level '{}'", s),
+            )),
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// One `key=value` line per entry, for humans
+    Text,
+    /// One JSON object per line, for log pipelines
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown log format '{}'", s),
+            )),
+        }
+    }
+}
+
+/// Sets what gets logged and how, for the whole process. Until then, `info` and above are
+/// logged as text.
+pub fn init(level: Level, format: Format) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed) && !SUSPENDED.load(Ordering::Relaxed)
+}
+
+/// Drops every entry until resumed, for them not to be drawn over a full-screen view
+pub fn suspend(suspended: bool) {
+    SUSPENDED.store(suspended, Ordering::Relaxed);
+}
+
+/// Writes an entry to stderr, used through the [`crate::info!`] family of macros
+#[doc(hidden)]
+pub fn write(level: Level, message: &str, fields: &[(&str, String)]) {
+    let format = if JSON.load(Ordering::Relaxed) {
+        Format::Json
+    } else {
+        Format::Text
+    };
+    let line = render(format, SystemTime::now(), level, message, fields);
+    let _ = writeln!(io::stderr().lock(), "{}", line);
+}
+
+fn render(
+    format: Format,
+    time: SystemTime,
+    level: Level,
+    message: &str,
+    fields: &[(&str, String)],
+) -> String {
+    match format {
+        Format::Text => {
+            let mut line = format!(
+                "{} {:<5} {}",
+                timestamp(time),
+                level.to_string().to_uppercase(),
+                message
+            );
+            for (key, value) in fields {
+                // Quoted when it would not read as a single value
+                if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"') {
+                    line.push_str(&format!(" {}={:?}", key, value));
+                } else {
+                    line.push_str(&format!(" {}={}", key, value));
+                }
+            }
+            line
+        }
+        Format::Json => {
+            let mut entry = Map::new();
+            entry.insert("ts".into(), Value::from(timestamp(time)));
+            entry.insert("level".into(), Value::from(level.to_string()));
+            entry.insert("message".into(), Value::from(message));
+            for (key, value) in fields {
+                entry.insert(key.to_string(), Value::from(value.as_str()));
+            }
+            Value::Object(entry).to_string()
+        }
+    }
+}
+
+/// RFC 3339 in UTC, with milliseconds
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // Civil date from days since 1970-01-01, see
+    // http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Logs a message at the given level, with optional `key = value` fields before a `;`.
+///
+/// # Example
+///
+/// ```rust
+/// use naps::log::Level;
+/// use naps::{info, log, warn};
+///
+/// let subject = "orders.created";
+/// log!(Level::Debug, "read {} bytes", 42);
+/// warn!(route = "orders", subject = subject; "cannot publish: {}", "timeout");
+/// info!("source connected");
+/// ```
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write(
+                $level,
+                &format!($($arg)+),
+                &[$((stringify!($key), $value.to_string())),+],
+            );
+        }
+    };
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write($level, &format!($($arg)+), &[]);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, timestamp, Format, Level};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let leap_day = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(timestamp(leap_day), "2024-02-29T12:34:56.789Z");
+    }
+
+    #[test]
+    fn levels() {
+        assert!(Level::Error < Level::Warn);
+        assert_eq!("WARN".parse::<Level>().unwrap(), Level::Warn);
+        assert!("verbose".parse::<Level>().is_err());
+    }
+
+    #[test]
+    fn formats() {
+        let fields = [
+            ("route", "orders".to_string()),
+            ("error", "timed out".to_string()),
+        ];
+
+        let text = render(
+            Format::Text,
+            UNIX_EPOCH,
+            Level::Warn,
+            "cannot publish",
+            &fields,
+        );
+        assert_eq!(
+            text,
+            r#"1970-01-01T00:00:00.000Z WARN  cannot publish route=orders error="timed out""#
+        );
+
+        let json = render(
+            Format::Json,
+            UNIX_EPOCH,
+            Level::Warn,
+            "cannot publish",
+            &fields,
+        );
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["level"], "warn");
+        assert_eq!(value["message"], "cannot publish");
+        assert_eq!(value["route"], "orders");
+        assert_eq!(value["error"], "timed out");
+        assert_eq!(value["ts"], "1970-01-01T00:00:00.000Z");
+    }
+}
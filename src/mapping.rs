@@ -0,0 +1,301 @@
+use crate::msg::Msg;
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Pattern {
+    Literal(String),
+    /// `*`, matches exactly one token
+    Single,
+    /// `>`, matches one or more trailing tokens
+    Tail,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Replacement {
+    Literal(String),
+    /// `{n}`, the token matched by the n-th `*` of the source, starting at 1
+    Wildcard(usize),
+    /// `>`, the tokens matched by the source `>`
+    Tail,
+}
+
+/// Renames subjects matching `from` after the `to` template, like NATS server subject mappings.
+///
+/// # Example
+///
+/// ```rust
+/// use naps::mapping::SubjectMapping;
+///
+/// let mapping: SubjectMapping = "orders.*.created=eu.{1}.created".parse().unwrap();
+/// assert_eq!(mapping.map("orders.42.created"), Some("eu.42.created".to_string()));
+/// assert_eq!(mapping.map("orders.42.deleted"), None);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubjectMapping {
+    from: Vec<Pattern>,
+    to: Vec<Replacement>,
+}
+
+impl SubjectMapping {
+    pub fn new(from: &str, to: &str) -> Result<Self> {
+        let invalid = |reason: String| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid mapping {} -> {}: {}", from, to, reason),
+            )
+        };
+
+        let from_tokens: Vec<&str> = from.split('.').collect();
+        let mut patterns = Vec::with_capacity(from_tokens.len());
+        for (i, token) in from_tokens.iter().enumerate() {
+            patterns.push(match *token {
+                "" => return Err(invalid("empty token".to_string())),
+                "*" => Pattern::Single,
+                ">" if i == from_tokens.len() - 1 => Pattern::Tail,
+                ">" => return Err(invalid("'>' must be the last token".to_string())),
+                literal => Pattern::Literal(literal.to_string()),
+            });
+        }
+
+        let wildcards = patterns.iter().filter(|p| **p == Pattern::Single).count();
+        let has_tail = patterns.last() == Some(&Pattern::Tail);
+
+        let to_tokens: Vec<&str> = to.split('.').collect();
+        let mut replacements = Vec::with_capacity(to_tokens.len());
+        for (i, token) in to_tokens.iter().enumerate() {
+            replacements.push(match *token {
+                "" => return Err(invalid("empty token".to_string())),
+                "*" => return Err(invalid("use {n} to reuse a wildcard".to_string())),
+                ">" if !has_tail => return Err(invalid("source has no '>'".to_string())),
+                ">" if i == to_tokens.len() - 1 => Replacement::Tail,
+                ">" => return Err(invalid("'>' must be the last token".to_string())),
+                token if token.starts_with('{') && token.ends_with('}') => {
+                    let n: usize = token[1..token.len() - 1]
+                        .parse()
+                        .map_err(|_| invalid(format!("bad wildcard reference {}", token)))?;
+                    if n == 0 || n > wildcards {
+                        return Err(invalid(format!("source has no wildcard {}", token)));
+                    }
+                    Replacement::Wildcard(n)
+                }
+                literal => Replacement::Literal(literal.to_string()),
+            });
+        }
+
+        Ok(Self {
+            from: patterns,
+            to: replacements,
+        })
+    }
+
+    /// Returns the new subject, or `None` if the subject does not match.
+    pub fn map(&self, subject: &str) -> Option<String> {
+        let mut captures: Vec<&str> = Vec::new();
+        let mut tail: Option<&str> = None;
+        let mut offset = 0;
+        let mut tokens = subject.split('.');
+
+        for pattern in self.from.iter() {
+            if *pattern == Pattern::Tail {
+                // The rest of the subject, which must hold at least one token
+                if offset >= subject.len() {
+                    return None;
+                }
+                tail = Some(&subject[offset..]);
+                break;
+            }
+
+            let token = tokens.next()?;
+            offset += token.len() + 1;
+
+            match pattern {
+                Pattern::Literal(literal) if literal != token => return None,
+                Pattern::Single => captures.push(token),
+                _ => {}
+            }
+        }
+
+        if tail.is_none() && tokens.next().is_some() {
+            return None;
+        }
+
+        let mut mapped = String::with_capacity(subject.len());
+        for (i, replacement) in self.to.iter().enumerate() {
+            if i > 0 {
+                mapped.push('.');
+            }
+            match replacement {
+                Replacement::Literal(literal) => mapped.push_str(literal),
+                Replacement::Wildcard(n) => mapped.push_str(captures[n - 1]),
+                Replacement::Tail => mapped.push_str(tail.unwrap_or_default()),
+            }
+        }
+
+        Some(mapped)
+    }
+}
+
+/// Parses `from=to`, the format of the `--map` flag
+impl FromStr for SubjectMapping {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some((from, to)) => Self::new(from.trim(), to.trim()),
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid mapping {}, expected from=to", s),
+            )),
+        }
+    }
+}
+
+impl Display for SubjectMapping {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let from: Vec<String> = self
+            .from
+            .iter()
+            .map(|p| match p {
+                Pattern::Literal(literal) => literal.clone(),
+                Pattern::Single => "*".to_string(),
+                Pattern::Tail => ">".to_string(),
+            })
+            .collect();
+        let to: Vec<String> = self
+            .to
+            .iter()
+            .map(|r| match r {
+                Replacement::Literal(literal) => literal.clone(),
+                Replacement::Wildcard(n) => format!("{{{}}}", n),
+                Replacement::Tail => ">".to_string(),
+            })
+            .collect();
+        write!(f, "{}={}", from.join("."), to.join("."))
+    }
+}
+
+/// Whether a message published to `subject` is received by a subscription to `pattern`
+pub fn matches(pattern: &str, subject: &str) -> bool {
+    let mut tokens = subject.split('.');
+    for expected in pattern.split('.') {
+        match (expected, tokens.next()) {
+            (_, None) => return false,
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (expected, Some(token)) if expected == token => {}
+            _ => return false,
+        }
+    }
+    tokens.next().is_none()
+}
+
+/// Ordered list of mappings, the first one matching a subject wins.
+#[derive(Debug, Clone, Default)]
+pub struct Mappings(pub Vec<SubjectMapping>);
+
+impl Mappings {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn map(&self, subject: &str) -> Option<String> {
+        self.0.iter().find_map(|mapping| mapping.map(subject))
+    }
+
+    /// Renames the message topic, leaving it untouched if no mapping matches
+    pub fn apply(&self, msg: &mut Msg) {
+        if let Some(topic) = self.map(&msg.topic) {
+            msg.topic = topic;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{matches, Mappings, SubjectMapping};
+
+    fn mapping(from: &str, to: &str) -> SubjectMapping {
+        SubjectMapping::new(from, to).unwrap()
+    }
+
+    #[test]
+    fn literal_mapping() {
+        let m = mapping("orders.created", "mirror.orders.created");
+        assert_eq!(
+            m.map("orders.created"),
+            Some("mirror.orders.created".into())
+        );
+        assert_eq!(m.map("orders.created.eu"), None);
+        assert_eq!(m.map("orders"), None);
+        assert_eq!(m.map("orders.deleted"), None);
+    }
+
+    #[test]
+    fn wildcard_mapping() {
+        let m = mapping("orders.*.*", "{2}.orders.{1}");
+        assert_eq!(m.map("orders.42.created"), Some("created.orders.42".into()));
+        assert_eq!(m.map("orders.42"), None);
+        assert_eq!(m.map("orders.42.created.eu"), None);
+    }
+
+    #[test]
+    fn tail_mapping() {
+        let m = mapping("orders.>", "mirror.orders.>");
+        assert_eq!(
+            m.map("orders.created"),
+            Some("mirror.orders.created".into())
+        );
+        assert_eq!(
+            m.map("orders.eu.42.created"),
+            Some("mirror.orders.eu.42.created".into())
+        );
+        assert_eq!(m.map("orders"), None);
+
+        let m = mapping("orders.*.>", "{1}.>");
+        assert_eq!(m.map("orders.eu.42.created"), Some("eu.42.created".into()));
+    }
+
+    #[test]
+    fn subscription_matches() {
+        assert!(matches("orders.created", "orders.created"));
+        assert!(matches("orders.*", "orders.created"));
+        assert!(matches("orders.>", "orders.eu.created"));
+        assert!(matches(">", "orders"));
+        assert!(!matches("orders.*", "orders.eu.created"));
+        assert!(!matches("orders.>", "orders"));
+        assert!(!matches("orders.created", "orders"));
+    }
+
+    #[test]
+    fn invalid_mappings() {
+        assert!(SubjectMapping::new("orders.>.created", "a.>").is_err());
+        assert!(SubjectMapping::new("orders.*", "a.>").is_err());
+        assert!(SubjectMapping::new("orders.*", "a.{2}").is_err());
+        assert!(SubjectMapping::new("orders.*", "a.{0}").is_err());
+        assert!(SubjectMapping::new("orders.*", "a.*").is_err());
+        assert!(SubjectMapping::new("orders..x", "a").is_err());
+        assert!("orders.created".parse::<SubjectMapping>().is_err());
+    }
+
+    #[test]
+    fn parse_and_display() {
+        let m: SubjectMapping = "orders.*.> = eu.{1}.>".parse().unwrap();
+        assert_eq!(m.to_string(), "orders.*.>=eu.{1}.>");
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let mappings = Mappings(vec![
+            mapping("orders.eu.>", "eu.>"),
+            mapping("orders.>", "global.>"),
+        ]);
+        assert_eq!(mappings.map("orders.eu.created"), Some("eu.created".into()));
+        assert_eq!(
+            mappings.map("orders.us.created"),
+            Some("global.us.created".into())
+        );
+        assert_eq!(mappings.map("users.created"), None);
+    }
+}
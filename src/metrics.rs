@@ -0,0 +1,494 @@
+use crate::conn::Side;
+use crate::health::ScriptStatus;
+use crossbeam::channel::Receiver;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Subjects with their own counters in each route, the others are counted under
+/// [`OTHER_SUBJECT`]. Subjects often hold ids, which would otherwise grow the metrics forever.
+pub const MAX_SUBJECTS: usize = 1000;
+
+/// Stands for the subjects past [`MAX_SUBJECTS`]
+pub const OTHER_SUBJECT: &str = "other";
+
+/// Counters kept for every subject of a route.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counters {
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+    /// Given up on, the source may send them again
+    pub dropped: u64,
+    pub script_errors: u64,
+    /// Discarded on purpose by the processing script
+    pub script_filtered: u64,
+    pub publish_errors: u64,
+    /// Handed to the dead-letter destination
+    pub dead_letters: u64,
+}
+
+/// Name, description and value of a counter
+type CounterSpec = (&'static str, &'static str, fn(&Counters) -> u64);
+
+/// Every counter, in the order they are rendered
+const COUNTERS: [CounterSpec; 9] = [
+    ("messages_in", "Messages read from the source", |c| {
+        c.messages_in
+    }),
+    ("bytes_in", "Payload bytes read from the source", |c| {
+        c.bytes_in
+    }),
+    (
+        "messages_out",
+        "Messages confirmed by the destination",
+        |c| c.messages_out,
+    ),
+    (
+        "bytes_out",
+        "Payload bytes confirmed by the destination",
+        |c| c.bytes_out,
+    ),
+    ("dropped", "Messages given up on", |c| c.dropped),
+    ("script_errors", "Messages the script threw on", |c| {
+        c.script_errors
+    }),
+    ("script_filtered", "Messages discarded by the script", |c| {
+        c.script_filtered
+    }),
+    (
+        "publish_errors",
+        "Failed publishes to the destination",
+        |c| c.publish_errors,
+    ),
+    (
+        "dead_letters",
+        "Messages stored in the dead-letter destination",
+        |c| c.dead_letters,
+    ),
+];
+
+/// Counts of observations per bucket, Prometheus style: every bucket includes the ones below.
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Length of a channel between two stages, read when metrics are scraped
+type Depth = Box<dyn Fn() -> usize + Send>;
+
+#[derive(Default)]
+struct Registry {
+    /// By route, then subject
+    counters: BTreeMap<(String, String), Counters>,
+    /// Subjects with their own counters, by route
+    subjects: BTreeMap<String, usize>,
+    /// By route
+    latency: BTreeMap<String, Histogram>,
+    /// By route, then queue name
+    queues: BTreeMap<(String, String), Depth>,
+    /// Whether each side is connected, by route then side
+    connections: BTreeMap<(String, String), bool>,
+    /// By route, for routes with a script
+    scripts: BTreeMap<String, ScriptStatus>,
+    /// Why routes stopped, by route
+    stopped: BTreeMap<String, String>,
+}
+
+/// Metrics of every route, rendered in the Prometheus text format.
+///
+/// # Example
+///
+/// ```rust
+/// use naps::metrics::Metrics;
+///
+/// let metrics = Metrics::default();
+/// metrics.route("orders").received("orders.created", 42);
+///
+/// assert!(metrics
+///     .render()
+///     .contains(r#"naps_messages_in_total{route="orders",subject="orders.created"} 1"#));
+/// ```
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Mutex<Registry>>);
+
+impl Metrics {
+    /// Handle the loops of one route record their metrics through
+    pub fn route(&self, name: &str) -> RouteMetrics {
+        RouteMetrics {
+            route: name.to_string(),
+            metrics: self.clone(),
+        }
+    }
+
+    /// Counters of one subject of a route, zeroed when nothing happened on it yet or when it is
+    /// counted under [`OTHER_SUBJECT`]
+    pub fn counters(&self, route: &str, subject: &str) -> Counters {
+        let registry = self.0.lock().unwrap();
+        registry
+            .counters
+            .get(&(route.to_string(), subject.to_string()))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Route, name and current length of every queue
+    pub fn queue_depths(&self) -> Vec<(String, String, usize)> {
+        let registry = self.0.lock().unwrap();
+        registry
+            .queues
+            .iter()
+            .map(|((route, queue), depth)| (route.clone(), queue.clone(), depth()))
+            .collect()
+    }
+
+    /// Route, side and state of every connection
+    pub fn connections(&self) -> Vec<(String, String, bool)> {
+        let registry = self.0.lock().unwrap();
+        registry
+            .connections
+            .iter()
+            .map(|((route, side), up)| (route.clone(), side.clone(), *up))
+            .collect()
+    }
+
+    /// Route and state of every script
+    pub fn scripts(&self) -> Vec<(String, ScriptStatus)> {
+        let registry = self.0.lock().unwrap();
+        registry
+            .scripts
+            .iter()
+            .map(|(route, status)| (route.clone(), status.clone()))
+            .collect()
+    }
+
+    /// Route and error of every route that stopped on its own
+    pub fn stopped(&self) -> Vec<(String, String)> {
+        let registry = self.0.lock().unwrap();
+        registry
+            .stopped
+            .iter()
+            .map(|(route, error)| (route.clone(), error.clone()))
+            .collect()
+    }
+
+    pub fn render(&self) -> String {
+        let registry = self.0.lock().unwrap();
+        let mut out = String::new();
+
+        for (name, help, value) in COUNTERS {
+            let _ = writeln!(out, "# HELP naps_{}_total {}", name, help);
+            let _ = writeln!(out, "# TYPE naps_{}_total counter", name);
+            for ((route, subject), c) in registry.counters.iter() {
+                let _ = writeln!(
+                    out,
+                    "naps_{}_total{{route=\"{}\",subject=\"{}\"}} {}",
+                    name,
+                    escape(route),
+                    escape(subject),
+                    value(c)
+                );
+            }
+        }
+
+        out.push_str("# HELP naps_queue_depth Messages waiting in front of a stage\n");
+        out.push_str("# TYPE naps_queue_depth gauge\n");
+        for ((route, queue), depth) in registry.queues.iter() {
+            let _ = writeln!(
+                out,
+                "naps_queue_depth{{route=\"{}\",queue=\"{}\"}} {}",
+                escape(route),
+                escape(queue),
+                depth()
+            );
+        }
+
+        out.push_str("# HELP naps_connected Whether a side of a route is connected\n");
+        out.push_str("# TYPE naps_connected gauge\n");
+        for ((route, side), up) in registry.connections.iter() {
+            let _ = writeln!(
+                out,
+                "naps_connected{{route=\"{}\",side=\"{}\"}} {}",
+                escape(route),
+                side,
+                *up as u8
+            );
+        }
+
+        out.push_str("# HELP naps_script_loaded Whether the script of a route is running\n");
+        out.push_str("# TYPE naps_script_loaded gauge\n");
+        for (route, status) in registry.scripts.iter() {
+            let _ = writeln!(
+                out,
+                "naps_script_loaded{{route=\"{}\"}} {}",
+                escape(route),
+                (*status == ScriptStatus::Loaded) as u8
+            );
+        }
+
+        out.push_str("# HELP naps_latency_seconds From reading a message to its confirmation\n");
+        out.push_str("# TYPE naps_latency_seconds histogram\n");
+        for (route, histogram) in registry.latency.iter() {
+            let route = escape(route);
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                let _ = writeln!(
+                    out,
+                    "naps_latency_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                    route, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "naps_latency_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}",
+                route, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "naps_latency_seconds_sum{{route=\"{}\"}} {}",
+                route, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "naps_latency_seconds_count{{route=\"{}\"}} {}",
+                route, histogram.count
+            );
+        }
+
+        out
+    }
+
+    fn count(&self, route: &str, subject: &str, update: impl FnOnce(&mut Counters)) {
+        let mut registry = self.0.lock().unwrap();
+        let mut key = (route.to_string(), subject.to_string());
+        if !registry.counters.contains_key(&key) {
+            let tracked = registry.subjects.entry(route.to_string()).or_default();
+            if *tracked < MAX_SUBJECTS {
+                *tracked += 1;
+            } else {
+                key.1 = OTHER_SUBJECT.to_string();
+            }
+        }
+        update(registry.counters.entry(key).or_default());
+    }
+}
+
+impl Debug for Metrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+/// Label values are quoted, so quotes, backslashes and line breaks are escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// Metrics of one route, cloned into each of its loops.
+#[derive(Debug, Clone, Default)]
+pub struct RouteMetrics {
+    route: String,
+    metrics: Metrics,
+}
+
+impl RouteMetrics {
+    pub fn received(&self, subject: &str, bytes: usize) {
+        self.metrics.count(&self.route, subject, |c| {
+            c.messages_in += 1;
+            c.bytes_in += bytes as u64;
+        });
+    }
+
+    /// The destination confirmed a message read at `read_at`
+    pub fn published(&self, subject: &str, bytes: usize, read_at: Instant) {
+        self.metrics.count(&self.route, subject, |c| {
+            c.messages_out += 1;
+            c.bytes_out += bytes as u64;
+        });
+        let mut registry = self.metrics.0.lock().unwrap();
+        registry
+            .latency
+            .entry(self.route.clone())
+            .or_default()
+            .observe(read_at.elapsed().as_secs_f64());
+    }
+
+    pub fn dropped(&self, subject: &str) {
+        self.metrics.count(&self.route, subject, |c| c.dropped += 1);
+    }
+
+    pub fn script_error(&self, subject: &str) {
+        self.metrics
+            .count(&self.route, subject, |c| c.script_errors += 1);
+    }
+
+    pub fn script_filtered(&self, subject: &str) {
+        self.metrics
+            .count(&self.route, subject, |c| c.script_filtered += 1);
+    }
+
+    pub fn publish_error(&self, subject: &str) {
+        self.metrics
+            .count(&self.route, subject, |c| c.publish_errors += 1);
+    }
+
+    pub fn dead_lettered(&self, subject: &str) {
+        self.metrics
+            .count(&self.route, subject, |c| c.dead_letters += 1);
+    }
+
+    pub fn queue(&self, name: &str, depth: impl Fn() -> usize + Send + 'static) {
+        let mut registry = self.metrics.0.lock().unwrap();
+        let key = (self.route.clone(), name.to_string());
+        registry.queues.insert(key, Box::new(depth));
+    }
+
+    /// Reports the length of the channel read from `rc`, handed back to the loop reading it.
+    /// Only a weak reference is kept so the channel still disconnects once that loop is gone.
+    pub fn watch<T: Send + 'static>(&self, name: &str, rc: Receiver<T>) -> Arc<Receiver<T>> {
+        let rc = Arc::new(rc);
+        let weak = Arc::downgrade(&rc);
+        self.queue(name, move || weak.upgrade().map_or(0, |rc| rc.len()));
+        rc
+    }
+
+    pub fn connection(&self, side: Side, up: bool) {
+        let mut registry = self.metrics.0.lock().unwrap();
+        let key = (self.route.clone(), side.to_string());
+        registry.connections.insert(key, up);
+    }
+
+    pub fn script(&self, status: ScriptStatus) {
+        let mut registry = self.metrics.0.lock().unwrap();
+        registry.scripts.insert(self.route.clone(), status);
+    }
+
+    /// The route gave up, its loops are gone
+    pub fn stop(&self, error: &str) {
+        let mut registry = self.metrics.0.lock().unwrap();
+        registry
+            .stopped
+            .insert(self.route.clone(), error.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, Metrics, MAX_SUBJECTS, OTHER_SUBJECT};
+    use crossbeam::channel::bounded;
+    use std::time::Instant;
+
+    #[test]
+    fn counters_by_route_and_subject() {
+        let metrics = Metrics::default();
+        let orders = metrics.route("orders");
+        orders.received("orders.created", 10);
+        orders.received("orders.created", 5);
+        orders.published("mirror.orders.created", 15, Instant::now());
+        orders.script_filtered("orders.created");
+        metrics.route("users").publish_error("users.created");
+
+        let created = metrics.counters("orders", "orders.created");
+        assert_eq!(created.messages_in, 2);
+        assert_eq!(created.bytes_in, 15);
+        assert_eq!(created.script_filtered, 1);
+        assert_eq!(created.messages_out, 0);
+        assert_eq!(
+            metrics
+                .counters("orders", "mirror.orders.created")
+                .bytes_out,
+            15
+        );
+        assert_eq!(metrics.counters("users", "users.created").publish_errors, 1);
+        assert_eq!(metrics.counters("users", "orders.created").messages_in, 0);
+    }
+
+    #[test]
+    fn subjects_are_capped() {
+        let metrics = Metrics::default();
+        let orders = metrics.route("orders");
+        for i in 0..MAX_SUBJECTS + 2 {
+            orders.received(&format!("orders.{}", i), 1);
+        }
+        // Tracked before the cap was reached
+        orders.received("orders.0", 1);
+        metrics.route("users").received("users.created", 1);
+
+        assert_eq!(metrics.counters("orders", "orders.0").messages_in, 2);
+        assert_eq!(metrics.counters("orders", OTHER_SUBJECT).messages_in, 2);
+        assert_eq!(
+            metrics
+                .counters("orders", &format!("orders.{}", MAX_SUBJECTS))
+                .messages_in,
+            0
+        );
+        assert_eq!(metrics.counters("users", "users.created").messages_in, 1);
+    }
+
+    #[test]
+    fn render_text_format() {
+        let metrics = Metrics::default();
+        let orders = metrics.route("orders");
+        orders.published("orders.created", 3, Instant::now());
+        orders.queue("write", || 7);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE naps_messages_out_total counter\n"));
+        assert!(text
+            .contains("naps_messages_out_total{route=\"orders\",subject=\"orders.created\"} 1\n"));
+        assert!(text.contains("naps_queue_depth{route=\"orders\",queue=\"write\"} 7\n"));
+        assert!(text.contains("naps_latency_seconds_bucket{route=\"orders\",le=\"10\"} 1\n"));
+        assert!(text.contains("naps_latency_seconds_bucket{route=\"orders\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("naps_latency_seconds_count{route=\"orders\"} 1\n"));
+    }
+
+    #[test]
+    fn watched_queues_disconnect() {
+        let metrics = Metrics::default();
+        let (msg_sc, msg_rc) = bounded(4);
+        let msg_rc = metrics.route("orders").watch("write", msg_rc);
+        msg_sc.send(1).unwrap();
+        msg_sc.send(2).unwrap();
+        assert_eq!(
+            metrics.queue_depths(),
+            vec![("orders".into(), "write".into(), 2)]
+        );
+
+        // The reading loop exited, senders must not block on a full channel
+        drop(msg_rc);
+        assert!(msg_sc.send(3).is_err());
+        assert_eq!(
+            metrics.queue_depths(),
+            vec![("orders".into(), "write".into(), 0)]
+        );
+    }
+
+    #[test]
+    fn label_escaping() {
+        assert_eq!(escape(r#"a"b\c"#), r#"a\"b\\c"#);
+        assert_eq!(escape("a\nb"), r"a\nb");
+    }
+}
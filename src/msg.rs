@@ -1,20 +1,159 @@
+use crate::ack::AckHandle;
+use nats::header::HeaderMap;
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
+use std::time::Instant;
+
+/// NATS message headers, where a header can hold several values.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers(BTreeMap<String, Vec<String>>);
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// First value of the header
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .get(name)
+            .and_then(|values| values.first())
+            .map(String::as_str)
+    }
+
+    pub fn get_all(&self, name: &str) -> &[String] {
+        self.0.get(name).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Replaces every value of the header
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.0.insert(name.to_string(), vec![value.to_string()]);
+    }
+
+    /// Adds a value to the header, keeping the previous ones
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.0
+            .entry(name.to_string())
+            .or_default()
+            .push(value.to_string());
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Vec<String>> {
+        self.0.remove(name)
+    }
+
+    /// Every header with all its values
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Vec<String>)> {
+        self.0.iter()
+    }
+
+    pub fn from_nats(headers: &HeaderMap) -> Self {
+        let mut result = Self::new();
+        for (name, values) in headers.iter() {
+            for value in values.iter() {
+                result.append(name, value);
+            }
+        }
+        result
+    }
+
+    /// `None` when there is nothing to send, as plain publishes are cheaper
+    pub fn to_nats(&self) -> Option<HeaderMap> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let mut headers = HeaderMap::new();
+        for (name, values) in self.iter() {
+            for value in values.iter() {
+                headers.insert(name.as_str(), value.as_str());
+            }
+        }
+        Some(headers)
+    }
+}
+
+impl FromIterator<(String, String)> for Headers {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        let mut headers = Self::new();
+        for (name, value) in iter {
+            headers.append(&name, &value);
+        }
+        headers
+    }
+}
 
 #[derive(Debug)]
 pub struct Msg {
     pub data: Vec<u8>,
     pub topic: String,
+    pub headers: Headers,
+    /// Stable identifier the destination can use to discard duplicates
+    pub id: Option<String>,
+    /// Present when the source expects to be told once the message has been delivered
+    pub ack: Option<AckHandle>,
+    /// When the message was read, or taken off the spill queue
+    pub read_at: Instant,
 }
 
 impl Msg {
     pub fn new(data: Vec<u8>, topic: String) -> Self {
-        Self { topic, data }
+        Self {
+            topic,
+            data,
+            headers: Headers::new(),
+            id: None,
+            ack: None,
+            read_at: Instant::now(),
+        }
     }
 
     pub fn from_str(data: String, topic: String) -> Self {
         Self {
             topic,
             data: data.into_bytes(),
+            headers: Headers::new(),
+            id: None,
+            ack: None,
+            read_at: Instant::now(),
+        }
+    }
+
+    pub fn with_headers(mut self, headers: Headers) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    pub fn with_id(mut self, id: Option<String>) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn with_ack(mut self, ack: Option<AckHandle>) -> Self {
+        self.ack = ack;
+        self
+    }
+
+    pub fn with_read_at(mut self, read_at: Instant) -> Self {
+        self.read_at = read_at;
+        self
+    }
+
+    /// Acks the message to its source, if it came with an ack handle.
+    pub fn ack(&mut self) {
+        if let Some(ack) = self.ack.take() {
+            ack.ack();
+        }
+    }
+
+    /// Nacks the message to its source, if it came with an ack handle.
+    pub fn nack(&mut self) {
+        if let Some(ack) = self.ack.take() {
+            ack.nack();
         }
     }
 }
@@ -24,3 +163,27 @@ impl Display for Msg {
         write!(f, "{}", self.topic)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Headers;
+
+    #[test]
+    fn headers_values() {
+        let mut headers = Headers::new();
+        assert!(headers.is_empty());
+
+        headers.append("Trace-Id", "a");
+        headers.append("Trace-Id", "b");
+        assert_eq!(headers.get("Trace-Id"), Some("a"));
+        assert_eq!(headers.get_all("Trace-Id"), &["a", "b"]);
+
+        headers.insert("Trace-Id", "c");
+        assert_eq!(headers.get_all("Trace-Id"), &["c"]);
+        assert_eq!(headers.get("Nats-Msg-Id"), None);
+        assert!(headers.get_all("Nats-Msg-Id").is_empty());
+
+        headers.remove("Trace-Id");
+        assert!(headers.is_empty());
+    }
+}
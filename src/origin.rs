@@ -0,0 +1,108 @@
+use crate::msg::Headers;
+
+/// Header with the id of the `naps` process that relayed the message last
+pub const ORIGIN_HEADER: &str = "Naps-Origin";
+
+/// Header counting how many times the message has been relayed
+pub const HOPS_HEADER: &str = "Naps-Hops";
+
+/// Stamps relayed messages so they are never relayed back where they came from.
+///
+/// Messages stamped by this same process, or relayed `max_hops` times already, are considered
+/// loops. The first check covers bidirectional routes, the second one several `naps` processes
+/// relaying in opposite directions.
+///
+/// # Example
+///
+/// ```rust
+/// use naps::msg::Headers;
+/// use naps::origin::Origin;
+///
+/// let a = Origin::new("a".to_string(), 1);
+/// let mut headers = Headers::new();
+/// assert!(!a.is_loop(&headers));
+///
+/// a.stamp(&mut headers);
+/// assert!(a.is_loop(&headers));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Origin {
+    pub id: String,
+    /// Zero disables the hop limit
+    pub max_hops: u32,
+}
+
+impl Origin {
+    pub fn new(id: String, max_hops: u32) -> Self {
+        Self { id, max_hops }
+    }
+
+    pub fn hops(headers: &Headers) -> u32 {
+        headers
+            .get(HOPS_HEADER)
+            .and_then(|hops| hops.parse().ok())
+            .unwrap_or(0)
+    }
+
+    pub fn is_loop(&self, headers: &Headers) -> bool {
+        if headers.get(ORIGIN_HEADER) == Some(self.id.as_str()) {
+            return true;
+        }
+        self.max_hops > 0 && Self::hops(headers) >= self.max_hops
+    }
+
+    /// Marks the message as relayed by this process, once more
+    pub fn stamp(&self, headers: &mut Headers) {
+        let hops = Self::hops(headers) + 1;
+        headers.insert(ORIGIN_HEADER, &self.id);
+        headers.insert(HOPS_HEADER, &hops.to_string());
+    }
+}
+
+impl Default for Origin {
+    /// Random id, without hop limit
+    fn default() -> Self {
+        Self::new(nuid::next(), 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Origin, HOPS_HEADER, ORIGIN_HEADER};
+    use crate::msg::Headers;
+
+    #[test]
+    fn stamp_counts_hops() {
+        let a = Origin::new("a".to_string(), 0);
+        let b = Origin::new("b".to_string(), 0);
+        let mut headers = Headers::new();
+
+        a.stamp(&mut headers);
+        assert_eq!(headers.get(ORIGIN_HEADER), Some("a"));
+        assert_eq!(headers.get(HOPS_HEADER), Some("1"));
+
+        b.stamp(&mut headers);
+        assert_eq!(headers.get(ORIGIN_HEADER), Some("b"));
+        assert_eq!(Origin::hops(&headers), 2);
+    }
+
+    #[test]
+    fn loops() {
+        let a = Origin::new("a".to_string(), 2);
+        let b = Origin::new("b".to_string(), 2);
+        let mut headers = Headers::new();
+        assert!(!a.is_loop(&headers));
+
+        a.stamp(&mut headers);
+        // Relayed back to the process that relayed it
+        assert!(a.is_loop(&headers));
+        assert!(!b.is_loop(&headers));
+
+        b.stamp(&mut headers);
+        // Bounced between two processes
+        assert!(a.is_loop(&headers));
+
+        let unlimited = Origin::new("c".to_string(), 0);
+        assert!(!unlimited.is_loop(&headers));
+    }
+}
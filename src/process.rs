@@ -1,87 +1,663 @@
-use crate::msg::Msg;
-use crate::SimpleModuleLoader;
-use crossbeam::channel::{select, Receiver, Sender};
-use deno_core::anyhow::Error;
+use crate::ack::AckHandle;
+use crate::dead_letter::{DeadLetters, Stage};
+use crate::health::ScriptStatus;
+use crate::metrics::RouteMetrics;
+use crate::msg::{Headers, Msg};
+use crate::shutdown::Drain;
+use crate::stats::{Event, Outcome};
+use crate::write::MSG_ID_HEADER;
+use crate::{debug, error, info, warn};
+use crate::{SimpleModuleLoader, MAIN_MODULE};
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
+use deno_core::anyhow::anyhow;
 use deno_core::error::AnyError;
-use deno_core::futures::{FutureExt, TryFutureExt};
-use deno_core::RuntimeOptions;
-use deno_core::{v8, FsModuleLoader};
-use deno_core::{JsRuntime, ModuleSpecifier, NoopModuleLoader};
+use deno_core::futures::future::poll_fn;
+use deno_core::v8;
+use deno_core::{JsRuntime, ModuleSpecifier};
 use deno_runtime::deno_broadcast_channel::InMemoryBroadcastChannel;
 use deno_runtime::deno_web::BlobStore;
 use deno_runtime::permissions::Permissions;
 use deno_runtime::worker::MainWorker;
 use deno_runtime::worker::WorkerOptions;
 use deno_runtime::BootstrapOptions;
+use serde::{Deserialize, Serialize};
 use serde_v8::Serializable;
-use std::borrow::Borrow;
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::os::unix::fs::OpenOptionsExt;
-use std::path::Path;
+use std::collections::BTreeMap;
 use std::rc::Rc;
-use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
-use std::{thread, time};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// How long to wait for new messages before checking whether the drain expired
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long the event loop runs at most while promises are pending, before checking for new
+/// messages and expired promises
+const EVENT_LOOP_TICK: Duration = Duration::from_millis(10);
 
 fn get_error_class_name(e: &AnyError) -> &'static str {
     deno_runtime::errors::get_error_class_name(e).unwrap_or("Error")
 }
 
-pub fn process_loop(
-    script: String,
-    process_rc: Receiver<Msg>,
-    write_sc: Sender<Msg>,
-    shutdown_arc: Arc<AtomicBool>,
-) -> Result<(), AnyError> {
-    let code = format!(
+/// What `process_loop` reports to, besides the next stage.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessOptions {
+    /// Name of the route, for logs
+    pub route: String,
+    pub metrics: RouteMetrics,
+    /// Where messages the script threw on go
+    pub dead_letters: DeadLetters,
+    /// How long the promise returned by `recv` may take, zero for no limit
+    pub timeout: Duration,
+    /// Messages whose `recv` promise is pending at the same time, at least one
+    pub concurrency: usize,
+    /// Path or URL of the module to run instead of the inline script
+    pub script_file: Option<String>,
+}
+
+/// A new version of the processing script, sent when the configuration is read again
+#[derive(Debug, Clone, PartialEq)]
+pub enum Script {
+    Inline(String),
+    /// Path or URL of the module, fetched again on every reload
+    File(String),
+}
+
+/// A message whose `recv` returned a promise that has not settled yet
+struct Pending {
+    msg: Msg,
+    promise: v8::Global<v8::Promise>,
+    /// Given to `recv`, which may still edit them until the promise settles
+    headers: v8::Global<v8::Value>,
+    called_at: Instant,
+}
+
+/// Where messages go once `recv` is done with them
+struct Outcomes<'a> {
+    route: &'a str,
+    metrics: &'a RouteMetrics,
+    dead_letters: &'a DeadLetters,
+    write_sc: &'a Sender<Msg>,
+    stats_sc: &'a Sender<Event>,
+    drain: &'a Drain,
+}
+
+impl Outcomes<'_> {
+    /// The script threw, rejected, timed out or returned something invalid
+    fn failed(&self, msg: Msg, reason: &str) {
+        self.metrics.script_error(&msg.topic);
+        let event = Event::message(&msg.topic, msg.data.len(), Outcome::Failed);
+        let _ = self.stats_sc.send(event);
+        self.drain.dropped(1);
+        self.dead_letters.send(msg, Stage::Script, reason);
+    }
+
+    /// Publishes what `recv` returned for the message
+    fn returned(
+        &self,
+        scope: &mut v8::HandleScope,
+        mut msg: Msg,
+        headers: v8::Local<v8::Value>,
+        value: v8::Local<v8::Value>,
+    ) {
+        let route = self.route;
+
+        // Scripts may have edited the headers object they were given
+        match headers_from_v8(scope, headers) {
+            Ok(headers) => msg.headers = headers,
+            Err(e) => warn!(
+                route = route,
+                subject = msg.topic,
+                kind = "headers";
+                "invalid headers set by script: {}",
+                e
+            ),
+        }
+
+        let outputs = match outputs_from_v8(scope, value) {
+            Ok(outputs) => outputs,
+            Err(errors) => {
+                for e in &errors {
+                    warn!(
+                        route = route,
+                        subject = msg.topic,
+                        kind = "result";
+                        "invalid result returned by script: {}",
+                        e
+                    );
+                }
+                // Nothing is published unless every result is valid
+                self.failed(msg, &errors.join("; "));
+                return;
+            }
+        };
+
+        if outputs.is_empty() {
+            // Filtered out by the script, nothing else will deliver it
+            self.metrics.script_filtered(&msg.topic);
+            msg.ack();
+            return;
+        }
+
+        for msg in fan_out(msg, outputs) {
+            let _ = self.write_sc.send(msg);
+        }
+    }
+}
+
+/// Calls `recv` on the message. When it returns a promise, the message waits for it in the
+/// returned [`Pending`], otherwise it is done with.
+fn call(
+    runtime: &mut JsRuntime,
+    recv: &v8::Global<v8::Function>,
+    msg: Msg,
+    outcomes: &Outcomes,
+) -> Option<Pending> {
+    let scope = &mut runtime.handle_scope();
+    let recv_func_obj = v8::Local::new(scope, recv);
+    let recv_func_scope = &mut v8::TryCatch::new(scope);
+    let recv_ctx_this = v8::undefined(recv_func_scope).into();
+
+    let recv_args_topic = msg.topic.to_v8(recv_func_scope).unwrap();
+    let recv_args_data = bytes_to_v8(recv_func_scope, &msg.data);
+    let recv_args_headers = headers_to_v8(recv_func_scope, &msg.headers).unwrap();
+
+    let value = recv_func_obj.call(
+        recv_func_scope,
+        recv_ctx_this,
+        &[recv_args_topic, recv_args_data, recv_args_headers],
+    );
+
+    if let Some(exception) = recv_func_scope.exception() {
+        let exception = exception.to_rust_string_lossy(recv_func_scope);
+        warn!(
+            route = outcomes.route,
+            subject = msg.topic,
+            kind = "exception";
+            "deno exception: {}",
+            exception
+        );
+        outcomes.failed(msg, &exception);
+        return None;
+    }
+
+    // Nacked on drop
+    let value = value?;
+
+    if let Ok(promise) = v8::Local::<v8::Promise>::try_from(value) {
+        return Some(Pending {
+            msg,
+            promise: v8::Global::new(recv_func_scope, promise),
+            headers: v8::Global::new(recv_func_scope, recv_args_headers),
+            called_at: Instant::now(),
+        });
+    }
+
+    outcomes.returned(recv_func_scope, msg, recv_args_headers, value);
+    None
+}
+
+/// Drives the event loop until one of the promises settles
+fn poll_settled(
+    runtime: &mut JsRuntime,
+    cx: &mut Context,
+    pending: &[Pending],
+) -> Poll<Result<(), AnyError>> {
+    if let Poll::Ready(Err(e)) = runtime.poll_event_loop(cx, false) {
+        return Poll::Ready(Err(e));
+    }
+
+    let scope = &mut runtime.handle_scope();
+    let settled = pending.iter().any(|p| {
+        let promise = v8::Local::new(scope, &p.promise);
+        !matches!(promise.state(), v8::PromiseState::Pending)
+    });
+    if settled {
+        Poll::Ready(Ok(()))
+    } else {
+        Poll::Pending
+    }
+}
+
+/// Hands the messages whose promise settled, or took longer than `timeout`, over to `outcomes`.
+/// Results go on as their promises settle, not necessarily in the order messages were read.
+fn settle(
+    runtime: &mut JsRuntime,
+    pending: &mut Vec<Pending>,
+    timeout: Duration,
+    outcomes: &Outcomes,
+) {
+    let scope = &mut runtime.handle_scope();
+    let scope = &mut v8::TryCatch::new(scope);
+
+    let mut i = 0;
+    while i < pending.len() {
+        let promise = v8::Local::new(scope, &pending[i].promise);
+        let state = promise.state();
+        let expired = !timeout.is_zero() && pending[i].called_at.elapsed() >= timeout;
+        if matches!(state, v8::PromiseState::Pending) && !expired {
+            i += 1;
+            continue;
+        }
+
+        let Pending { msg, headers, .. } = pending.remove(i);
+        match state {
+            v8::PromiseState::Fulfilled => {
+                let value = promise.result(scope);
+                let headers = v8::Local::new(scope, &headers);
+                outcomes.returned(scope, msg, headers, value);
+            }
+            v8::PromiseState::Rejected => {
+                let reason = promise.result(scope).to_rust_string_lossy(scope);
+                warn!(
+                    route = outcomes.route,
+                    subject = msg.topic,
+                    kind = "rejection";
+                    "recv rejected: {}",
+                    reason
+                );
+                outcomes.failed(msg, &reason);
+            }
+            v8::PromiseState::Pending => {
+                let reason = format!("recv did not settle within {:?}", timeout);
+                warn!(
+                    route = outcomes.route,
+                    subject = msg.topic,
+                    kind = "timeout";
+                    "{}",
+                    reason
+                );
+                outcomes.failed(msg, &reason);
+            }
+        }
+    }
+}
+
+/// Scripts see headers as a `Record<string, string | string[]>`, with arrays only for headers
+/// holding several values.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum HeaderValue {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Payloads reach `recv` as a `Uint8Array` over a copy of the message data
+fn bytes_to_v8<'s>(scope: &mut v8::HandleScope<'s>, bytes: &[u8]) -> v8::Local<'s, v8::Value> {
+    let store =
+        v8::ArrayBuffer::new_backing_store_from_boxed_slice(bytes.to_vec().into_boxed_slice());
+    let buffer = v8::ArrayBuffer::with_backing_store(scope, &store.make_shared());
+    v8::Uint8Array::new(scope, buffer, 0, bytes.len())
+        .unwrap()
+        .into()
+}
+
+fn headers_to_v8<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    headers: &Headers,
+) -> Result<v8::Local<'s, v8::Value>, AnyError> {
+    let record: BTreeMap<&String, HeaderValue> = headers
+        .iter()
+        .map(|(name, values)| {
+            let value = match values.as_slice() {
+                [one] => HeaderValue::One(one.clone()),
+                many => HeaderValue::Many(many.to_vec()),
+            };
+            (name, value)
+        })
+        .collect();
+
+    Ok(serde_v8::to_v8(scope, record)?)
+}
+
+fn headers_from_v8(
+    scope: &mut v8::HandleScope,
+    value: v8::Local<v8::Value>,
+) -> Result<Headers, AnyError> {
+    let record: BTreeMap<String, HeaderValue> = serde_v8::from_v8(scope, value)?;
+    let mut headers = Headers::new();
+    for (name, value) in record {
+        match value {
+            HeaderValue::One(one) => headers.append(&name, &one),
+            HeaderValue::Many(many) => many.iter().for_each(|v| headers.append(&name, v)),
+        }
+    }
+
+    Ok(headers)
+}
+
+/// One message `recv` asked for
+#[derive(Debug, PartialEq)]
+enum Output {
+    /// `true`, the message goes on as it is
+    Forward,
+    /// A `RecvResult`, headers default to the ones of the original message
+    Publish {
+        topic: String,
+        data: Vec<u8>,
+        headers: Option<Headers>,
+    },
+}
+
+/// Validates what `recv` returned, a single result or an array of them. `false`, `null` and
+/// `undefined` publish nothing. Errors name the index of the result when it is in an array.
+fn outputs_from_v8(
+    scope: &mut v8::HandleScope,
+    value: v8::Local<v8::Value>,
+) -> Result<Vec<Output>, Vec<String>> {
+    let (items, in_array) = match v8::Local::<v8::Array>::try_from(value) {
+        Ok(array) => {
+            let mut items = vec![];
+            for i in 0..array.length() {
+                match array.get_index(scope, i) {
+                    Some(item) => items.push(item),
+                    None => return Err(vec![format!("cannot read result {}", i)]),
+                }
+            }
+            (items, true)
+        }
+        Err(_) => (vec![value], false),
+    };
+
+    let mut outputs = vec![];
+    let mut errors = vec![];
+    for (i, item) in items.into_iter().enumerate() {
+        match output_from_v8(scope, item) {
+            Ok(Some(output)) => outputs.push(output),
+            Ok(None) => {}
+            Err(e) if in_array => errors.push(format!("result {}: {}", i, e)),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(outputs)
+    } else {
+        Err(errors)
+    }
+}
+
+fn output_from_v8(
+    scope: &mut v8::HandleScope,
+    value: v8::Local<v8::Value>,
+) -> Result<Option<Output>, String> {
+    if value.is_true() {
+        return Ok(Some(Output::Forward));
+    }
+    if value.is_false() || value.is_null_or_undefined() {
+        return Ok(None);
+    }
+    if !value.is_object() || value.is_array() {
+        let kind = value.type_of(scope).to_rust_string_lossy(scope);
+        return Err(format!("expected a boolean or a RecvResult, got {}", kind));
+    }
+
+    let res = value
+        .to_object(scope)
+        .ok_or_else(|| "cannot read the RecvResult".to_string())?;
+    let topic_val = property(scope, res, "topic")?;
+    let data_val = property(scope, res, "msg")?;
+    let headers_val = property(scope, res, "headers")?;
+
+    if !topic_val.is_string() {
+        return Err("`topic` is not a string".to_string());
+    }
+    let topic = topic_val.to_rust_string_lossy(scope);
+    if topic.is_empty() {
+        return Err("`topic` is empty".to_string());
+    }
+    let data = data_from_v8(scope, data_val)?;
+    let headers = if headers_val.is_null_or_undefined() {
+        None
+    } else {
+        let headers =
+            headers_from_v8(scope, headers_val).map_err(|e| format!("invalid `headers`: {}", e))?;
+        Some(headers)
+    };
+
+    Ok(Some(Output::Publish {
+        topic,
+        data,
+        headers,
+    }))
+}
+
+/// Fails when a getter or a proxy throws, the exception is left to the enclosing `TryCatch`
+fn property<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    object: v8::Local<v8::Object>,
+    name: &str,
+) -> Result<v8::Local<'s, v8::Value>, String> {
+    let key = v8::String::new(scope, name).unwrap().into();
+    object
+        .get(scope, key)
+        .ok_or_else(|| format!("cannot read `{}`", name))
+}
+
+/// Payload of a `RecvResult`. Strings are encoded in UTF-8, `Uint8Array`s and other views,
+/// as well as `ArrayBuffer`s, are copied byte for byte, and other objects are serialized to
+/// JSON.
+fn data_from_v8(
+    scope: &mut v8::HandleScope,
+    value: v8::Local<v8::Value>,
+) -> Result<Vec<u8>, String> {
+    if value.is_string() {
+        return Ok(value.to_rust_string_lossy(scope).into_bytes());
+    }
+
+    if let Ok(buffer) = v8::Local::<v8::ArrayBuffer>::try_from(value) {
+        let view = v8::Uint8Array::new(scope, buffer, 0, buffer.byte_length())
+            .ok_or_else(|| "cannot read `msg` buffer".to_string())?;
+        return Ok(view_bytes(view.into()));
+    }
+    if let Ok(view) = v8::Local::<v8::ArrayBufferView>::try_from(value) {
+        return Ok(view_bytes(view));
+    }
+
+    if value.is_object() && !value.is_function() {
+        let json = v8::json::stringify(scope, value)
+            .ok_or_else(|| "`msg` cannot be serialized to JSON".to_string())?;
+        return Ok(json.to_rust_string_lossy(scope).into_bytes());
+    }
+
+    let kind = value.type_of(scope).to_rust_string_lossy(scope);
+    Err(format!(
+        "`msg` must be a string, a Uint8Array, an ArrayBuffer or an object, got {}",
+        kind
+    ))
+}
+
+fn view_bytes(view: v8::Local<v8::ArrayBufferView>) -> Vec<u8> {
+    let mut bytes = vec![0; view.byte_length()];
+    view.copy_contents(&mut bytes);
+    bytes
+}
+
+/// Turns the message into the ones `recv` asked for. They share its ack, so the source is told
+/// it was delivered once all of them are. Their ids get the index of the result, for the
+/// destination not to discard them as duplicates of each other.
+fn fan_out(mut msg: Msg, outputs: Vec<Output>) -> Vec<Msg> {
+    let count = outputs.len();
+    let mut acks: Vec<Option<AckHandle>> = match msg.ack.take() {
+        Some(ack) => ack.split(count).into_iter().map(Some).collect(),
+        None => (0..count).map(|_| None).collect(),
+    };
+
+    outputs
+        .into_iter()
+        .enumerate()
+        .map(|(i, output)| {
+            let (topic, data, mut headers) = match output {
+                Output::Forward => (msg.topic.clone(), msg.data.clone(), msg.headers.clone()),
+                Output::Publish {
+                    topic,
+                    data,
+                    headers,
+                } => (topic, data, headers.unwrap_or_else(|| msg.headers.clone())),
+            };
+            let mut id = msg.id.clone();
+            if count > 1 {
+                id = id.map(|id| format!("{}-{}", id, i));
+                if let Some(publisher_id) = headers.get(MSG_ID_HEADER) {
+                    let publisher_id = format!("{}-{}", publisher_id, i);
+                    headers.insert(MSG_ID_HEADER, &publisher_id);
+                }
+            }
+
+            Msg::new(data, topic)
+                .with_headers(headers)
+                .with_id(id)
+                .with_ack(acks[i].take())
+                .with_read_at(msg.read_at)
+        })
+        .collect()
+}
+
+/// Defines `globalThis.recv` from the `recv` in scope, which forwards messages as they are
+/// when there is none.
+const RECV_WRAPPER: &str = r#"
+    if (typeof recv === 'function') {
+        const userRecv = recv;
+        globalThis.recv = function (...args) {
+            const result = userRecv(...args);
+            // Rejections are reported per message, not as unhandled ones
+            if (result instanceof Promise) {
+                result.catch(() => {});
+            }
+            return result;
+        };
+    } else {
+        globalThis.recv = function recv() {
+            return true;
+        };
+    }
+"#;
+
+/// Wraps the user code into a module defining `globalThis.recv`.
+fn module_code(script: &str) -> String {
+    format!(
         r#"
             import {{ Buffer }} from 'http://deno.land/x/node_buffer/index.ts';
 
             {}; // User code
 
-            if (recv && typeof recv === 'function') {{
-                globalThis.recv = recv;
-            }} else {{
-                globalThis.recv = function recv(topic, uint8array) {{
-                    return {{topic, msg: Buffer.from(uint8array).toString() }};
-                }};
-            }}
+            {}
         "#,
-        script
-    );
+        script, RECV_WRAPPER
+    )
+}
+
+/// Code defining `globalThis.recv` from the `recv` exported by a script file, or the one it
+/// set globally.
+fn file_wrapper_code(main_module: &ModuleSpecifier) -> String {
+    format!(
+        r#"
+            import * as main from '{}';
+
+            const recv = main.recv ?? globalThis.recv;
+
+            {}
+        "#,
+        main_module, RECV_WRAPPER
+    )
+}
+
+/// Gives file and http(s) modules a new URL for every version, so they are fetched and evaluated
+/// again. Data URLs carry their code, a new version already has a new URL.
+fn versioned(mut specifier: ModuleSpecifier, version: usize) -> ModuleSpecifier {
+    if matches!(specifier.scheme(), "file" | "http" | "https") {
+        specifier
+            .query_pairs_mut()
+            .append_pair("version", &version.to_string());
+    }
+    specifier
+}
+
+/// The `recv` function last defined by a module, kept alive across handle scopes
+fn global_recv(runtime: &mut JsRuntime) -> Result<v8::Global<v8::Function>, AnyError> {
+    let scope = &mut runtime.handle_scope();
+    let context = scope.get_current_context();
+    let global = context.global(scope);
+    let recv_key = v8::String::new(scope, "recv").unwrap();
+    let recv = global
+        .get(scope, recv_key.into())
+        .ok_or_else(|| anyhow!("recv is not defined"))?;
+    let recv =
+        v8::Local::<v8::Function>::try_from(recv).map_err(|_| anyhow!("recv is not a function"))?;
 
-    // TODO: Windows support
-    // FIXME: A temporary named file is created to store the final JS code to be executed. This
-    // code cannot be injected by memory to deno main module for some reason that still needs
-    // to be investigated. What works it to send an existing file to `deno_core::resolve_path`.
-    let micro_seconds = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)?
-        .as_micros();
-    let path = format!("/tmp/{}.deno", micro_seconds.to_string());
-    let path_str = path.as_str();
-    // TODO: Check file does not exist
-    match std::fs::OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .open(path_str)
-    {
-        Err(e) => eprintln!("errors occurred when opening file: {}", e),
-        Ok(mut fd) => {
-            fd.write(code.as_bytes());
-            fd.flush()?;
+    Ok(v8::Global::new(scope, recv))
+}
+
+/// Evaluates a new version of the script next to the running one. Until it succeeds, the
+/// `recv` function in use stays the previous one. A new version without `recv` forwards every
+/// message, as the first one would. Only the main module of a script file is fetched again, the
+/// modules it imports stay the ones loaded on start.
+async fn reload(
+    worker: &mut MainWorker,
+    loader: &SimpleModuleLoader,
+    script: &Script,
+    version: usize,
+) -> Result<v8::Global<v8::Function>, AnyError> {
+    // Otherwise the wrapper finds the previous `recv` when the new version defines none. The one
+    // in use is held on this side, it keeps running until the reload succeeds.
+    worker
+        .js_runtime
+        .execute_script("naps:reload", "delete globalThis.recv;")?;
+    let module = match script {
+        Script::Inline(code) => {
+            let specifier = format!("{}?version={}", MAIN_MODULE, version);
+            loader.insert(&specifier, module_code(code))?
+        }
+        Script::File(file) => {
+            let main_module = versioned(deno_core::resolve_url_or_path(file)?, version);
+            let specifier = format!("naps:file?version={}", version);
+            loader.insert(&specifier, file_wrapper_code(&main_module))?
         }
     };
+    // Loaded by now, so only its code is dropped here. Every evaluated version still stays in the
+    // module map of the worker, reloads add up until the route restarts.
+    let executed = worker.execute_side_module(&module).await;
+    loader.remove(&module);
+    executed?;
+    worker.run_event_loop(false).await?;
+
+    global_recv(&mut worker.js_runtime)
+}
+
+/// Scripts received on `script_rc` replace the running one between two messages. Whether the
+/// script is running is reported to the health checks.
+pub fn process_loop(
+    script: String,
+    opts: ProcessOptions,
+    process_rc: &Receiver<Msg>,
+    write_sc: Sender<Msg>,
+    stats_sc: Sender<Event>,
+    script_rc: Receiver<Script>,
+    drain: Drain,
+) -> Result<(), AnyError> {
+    let ProcessOptions {
+        route,
+        metrics,
+        dead_letters,
+        timeout,
+        concurrency,
+        script_file,
+    } = opts;
+    let route = route.as_str();
+    metrics.script(ScriptStatus::Loading);
+    let status = metrics.clone();
 
     let tokio_runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()?;
 
     let future = async move {
-        let module_loader = Rc::new(SimpleModuleLoader);
+        let loader = Rc::new(SimpleModuleLoader::default());
+        // Script files are loaded from where they are, inline scripts from memory
+        let main_module = match &script_file {
+            Some(file) => deno_core::resolve_url_or_path(file)?,
+            None => loader.insert(MAIN_MODULE, module_code(&script))?,
+        };
+        let module_loader = Rc::clone(&loader);
         let create_web_worker_cb = Arc::new(|_| {
             todo!("Web workers are not supported ");
         });
@@ -118,7 +694,6 @@ pub fn process_loop(
             create_web_worker_cb,
         };
 
-        let main_module = deno_core::resolve_path(path_str.as_ref())?;
         let permissions = Permissions::allow_all();
 
         let mut worker =
@@ -126,98 +701,258 @@ pub fn process_loop(
 
         worker.execute_main_module(&main_module).await?;
         //println!("worker module main executed");
+        if script_file.is_some() {
+            let wrapper = loader.insert("naps:file", file_wrapper_code(&main_module))?;
+            worker.execute_side_module(&wrapper).await?;
+        }
 
         worker.run_event_loop(false).await?;
         //println!("worker event loop loaded");
 
-        let runtime = &mut worker.js_runtime;
-
-        let scope = &mut runtime.handle_scope();
-        let context = scope.get_current_context();
-        let global = context.global(scope);
-        let scope = &mut v8::ContextScope::new(scope, context);
-
-        let global_this_key = v8::String::new(scope, "globalThis").unwrap();
-        let global_this = global.get(scope, v8::Local::from(global_this_key)).unwrap();
-        let global_this_obj = v8::Local::<v8::Object>::try_from(global_this).unwrap();
-        let global_this_recv_key = v8::String::new(scope, "recv").unwrap();
-        let recv_func = global_this_obj
-            .get(scope, v8::Local::from(global_this_recv_key))
-            .unwrap();
-        let recv_func_obj = v8::Local::<v8::Function>::try_from(recv_func).unwrap();
-        let recv_func_scope = &mut v8::TryCatch::new(scope);
-        let recv_ctx_this = v8::undefined(recv_func_scope).into();
-
-        while !shutdown_arc.load(Ordering::Relaxed) {
-            let msg = process_rc.recv();
-            if msg.is_err() {
-                eprintln!("{}", msg.unwrap_err());
-                continue;
-            }
-
-            let msg = msg.unwrap();
+        let mut recv = global_recv(&mut worker.js_runtime)?;
+        let mut reloads = 0;
+        metrics.script(ScriptStatus::Loaded);
 
-            let recv_args_topic = msg.topic.to_v8(recv_func_scope).unwrap();
-            let recv_args_data = msg.data.to_v8(recv_func_scope).unwrap();
-
-            let value = recv_func_obj.call(
-                recv_func_scope,
-                recv_ctx_this,
-                &[recv_args_topic, recv_args_data],
-            );
+        let outcomes = Outcomes {
+            route,
+            metrics: &metrics,
+            dead_letters: &dead_letters,
+            write_sc: &write_sc,
+            stats_sc: &stats_sc,
+            drain: &drain,
+        };
+        let concurrency = concurrency.max(1);
+        let mut pending: Vec<Pending> = vec![];
+        let mut closed = false;
 
-            if let Some(exception) = recv_func_scope.exception() {
-                eprint!(
-                    "deno exception: { }",
-                    exception.to_rust_string_lossy(recv_func_scope)
-                );
-                continue;
+        // Until the reader is gone and everything it read went through the script
+        while !drain.is_expired() && !(closed && pending.is_empty()) {
+            if let Ok(script) = script_rc.try_recv() {
+                reloads += 1;
+                match reload(&mut worker, &loader, &script, reloads).await {
+                    Ok(new_recv) => {
+                        recv = new_recv;
+                        info!(route = route; "script reloaded");
+                    }
+                    Err(e) => error!(
+                        route = route,
+                        kind = "script";
+                        "cannot reload script, keeping the previous one: {}",
+                        e
+                    ),
+                }
             }
 
-            if value.is_none() {
-                continue;
+            // Only wait for messages when no promise needs the event loop
+            let mut wait = if pending.is_empty() {
+                POLL_INTERVAL
+            } else {
+                Duration::ZERO
+            };
+            for _ in pending.len()..concurrency {
+                match process_rc.recv_timeout(wait) {
+                    Ok(msg) => {
+                        if let Some(p) = call(&mut worker.js_runtime, &recv, msg, &outcomes) {
+                            pending.push(p);
+                        }
+                        wait = Duration::ZERO;
+                    }
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        closed = true;
+                        break;
+                    }
+                }
             }
 
-            let value = value.unwrap();
-
-            if value.is_boolean() && value.is_true() {
-                // Just forward the message
-                write_sc.send(msg);
-            } else if value.is_object() {
-                // Extract topic and msg
-                let res = value.to_object(recv_func_scope).unwrap();
-                let topic_key = v8::String::new(recv_func_scope, "topic").unwrap().into();
-                let msg_key = v8::String::new(recv_func_scope, "msg").unwrap().into();
-                let topic_val = res.get(recv_func_scope, topic_key).unwrap();
-                let data_val = res.get(recv_func_scope, msg_key).unwrap();
-
-                // TODO: data_val should support [u8]
-                if topic_val.is_string() && data_val.is_string() {
-                    let topic = topic_val
-                        .to_string(recv_func_scope)
-                        .unwrap()
-                        .to_rust_string_lossy(recv_func_scope);
-                    let data = data_val
-                        .to_string(recv_func_scope)
-                        .unwrap()
-                        .to_rust_string_lossy(recv_func_scope);
-
-                    let msg = Msg::from_str(data, topic);
-
-                    // Send message to channel
-                    write_sc.send(msg);
+            if !pending.is_empty() {
+                let runtime = &mut worker.js_runtime;
+                let settled = poll_fn(|cx| poll_settled(runtime, cx, &pending));
+                if let Ok(Err(e)) = tokio::time::timeout(EVENT_LOOP_TICK, settled).await {
+                    error!(route = route, kind = "script"; "uncaught error in script: {}", e);
                 }
+                settle(&mut worker.js_runtime, &mut pending, timeout, &outcomes);
             }
         }
+        // Left behind by an expired drain, nacked on drop
+        drain.dropped(process_rc.len() + pending.len());
 
         Ok(())
     };
 
     let res = tokio_runtime.block_on(future);
+    if let Err(e) = &res {
+        status.script(ScriptStatus::Failed(e.to_string()));
+    }
 
-    std::fs::remove_file(path_str)?;
-
-    eprintln!("process loop exited");
+    debug!(route = route; "process loop exited");
 
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        bytes_to_v8, call, data_from_v8, fan_out, outputs_from_v8, settle, versioned, Outcomes,
+        Output, Pending,
+    };
+    use crate::dead_letter::{DeadLetters, REASON_HEADER};
+    use crate::metrics::RouteMetrics;
+    use crate::msg::Msg;
+    use crate::shutdown::Drain;
+    use crate::write::MSG_ID_HEADER;
+    use crossbeam::channel::unbounded;
+    use deno_core::{v8, JsRuntime, RuntimeOptions};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn payloads_round_trip() {
+        let mut runtime = JsRuntime::new(RuntimeOptions::default());
+        let scope = &mut runtime.handle_scope();
+
+        let bytes = vec![0, 159, 146, 150, 255];
+        let value = bytes_to_v8(scope, &bytes);
+        assert!(value.is_uint8_array());
+        assert_eq!(data_from_v8(scope, value).unwrap(), bytes);
+
+        let empty = bytes_to_v8(scope, &[]);
+        assert_eq!(data_from_v8(scope, empty).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn throwing_results_are_errors() {
+        let mut runtime = JsRuntime::new(RuntimeOptions::default());
+        let value = runtime
+            .execute_script(
+                "result.js",
+                "({ get topic() { throw new Error('boom') }, msg: 'data' })",
+            )
+            .unwrap();
+        let scope = &mut runtime.handle_scope();
+        let scope = &mut v8::TryCatch::new(scope);
+
+        let value = v8::Local::new(scope, value);
+        let errors = outputs_from_v8(scope, value).unwrap_err();
+        assert_eq!(errors, vec!["cannot read `topic`".to_string()]);
+        assert!(scope.has_caught());
+    }
+
+    #[test]
+    fn promises_are_settled() {
+        let mut runtime = JsRuntime::new(RuntimeOptions::default());
+        let recv = runtime
+            .execute_script(
+                "recv.js",
+                "(topic) => topic === 'orders.created' ? Promise.resolve(true) \
+                 : topic === 'orders.failed' ? Promise.reject('boom') \
+                 : new Promise(() => {})",
+            )
+            .unwrap();
+        let recv = {
+            let scope = &mut runtime.handle_scope();
+            let recv = v8::Local::new(scope, recv);
+            v8::Global::new(scope, v8::Local::<v8::Function>::try_from(recv).unwrap())
+        };
+
+        let (write_sc, write_rc) = unbounded();
+        let (dead_letter_sc, dead_letter_rc) = unbounded();
+        let (stats_sc, stats_rc) = unbounded();
+        let metrics = RouteMetrics::default();
+        let dead_letters = DeadLetters::new("orders", dead_letter_sc);
+        let drain = Drain::new(Arc::new(AtomicBool::new(false)));
+        let outcomes = Outcomes {
+            route: "orders",
+            metrics: &metrics,
+            dead_letters: &dead_letters,
+            write_sc: &write_sc,
+            stats_sc: &stats_sc,
+            drain: &drain,
+        };
+
+        let mut pending: Vec<Pending> = ["orders.pending", "orders.failed", "orders.created"]
+            .iter()
+            .filter_map(|topic| {
+                let msg = Msg::from_str("{}".to_string(), topic.to_string());
+                call(&mut runtime, &recv, msg, &outcomes)
+            })
+            .collect();
+        assert_eq!(pending.len(), 3);
+
+        // No limit, only the pending one is left
+        settle(&mut runtime, &mut pending, Duration::ZERO, &outcomes);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(write_rc.try_recv().unwrap().topic, "orders.created");
+        let rejected = dead_letter_rc.try_recv().unwrap();
+        assert_eq!(rejected.topic, "orders.failed");
+        assert_eq!(rejected.headers.get(REASON_HEADER), Some("boom"));
+
+        let timeout = Duration::from_millis(10);
+        thread::sleep(timeout);
+        settle(&mut runtime, &mut pending, timeout, &outcomes);
+        assert!(pending.is_empty());
+        let expired = dead_letter_rc.try_recv().unwrap();
+        assert_eq!(expired.topic, "orders.pending");
+        assert!(expired
+            .headers
+            .get(REASON_HEADER)
+            .unwrap()
+            .starts_with("recv did not settle"));
+        assert!(write_rc.try_recv().is_err());
+        // Both count as errors on the dashboard
+        assert_eq!(stats_rc.len(), 2);
+    }
+
+    #[test]
+    fn fan_out_keeps_ids_apart() {
+        let mut msg = Msg::new(b"{}".to_vec(), "orders.created".to_string())
+            .with_id(Some("ORDERS.7".to_string()));
+        msg.headers.insert(MSG_ID_HEADER, "order-7");
+        let outputs = vec![
+            Output::Forward,
+            Output::Publish {
+                topic: "billing.created".to_string(),
+                data: b"7".to_vec(),
+                headers: None,
+            },
+        ];
+
+        let msgs = fan_out(msg, outputs);
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].topic, "orders.created");
+        assert_eq!(msgs[0].id.as_deref(), Some("ORDERS.7-0"));
+        assert_eq!(msgs[1].topic, "billing.created");
+        assert_eq!(msgs[1].data, b"7");
+        assert_eq!(msgs[1].id.as_deref(), Some("ORDERS.7-1"));
+        assert_eq!(msgs[1].headers.get(MSG_ID_HEADER), Some("order-7-1"));
+
+        // A single result keeps the id of the message it replaces
+        let msg =
+            Msg::new(vec![], "orders.created".to_string()).with_id(Some("ORDERS.8".to_string()));
+        let msgs = fan_out(msg, vec![Output::Forward]);
+        assert_eq!(msgs[0].id.as_deref(), Some("ORDERS.8"));
+    }
+
+    #[test]
+    fn script_files_get_a_url_per_version() {
+        let file = "file:///etc/naps/recv.ts".parse().unwrap();
+        assert_eq!(
+            versioned(file, 2).as_str(),
+            "file:///etc/naps/recv.ts?version=2"
+        );
+        let url = "https://example.com/recv.ts?ref=main".parse().unwrap();
+        assert_eq!(
+            versioned(url, 3).as_str(),
+            "https://example.com/recv.ts?ref=main&version=3"
+        );
+        let data = "data:application/javascript;base64,ZXhwb3J0IHt9"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            versioned(data, 4).as_str(),
+            "data:application/javascript;base64,ZXhwb3J0IHt9"
+        );
+    }
+}
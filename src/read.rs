@@ -1,42 +1,341 @@
-use crate::msg::Msg;
-use crossbeam::channel::{select, Receiver, Sender};
-use nats::Message;
+use crate::ack::{AckHandle, InFlight};
+use crate::metrics::RouteMetrics;
+use crate::msg::{Headers, Msg};
+use crate::origin::Origin;
+use crate::stats::{Event, Outcome};
+use crate::{debug, error, warn};
+use crossbeam::channel::{never, select, Receiver, RecvTimeoutError, Sender};
+use nats::jetstream::{AckPolicy, ConsumerConfig, SubscribeOptions};
+use nats::{Connection, Message};
+use std::collections::BTreeMap;
 use std::io::Result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::{thread, time};
 
+/// Durable JetStream consumer to read from instead of plain subscriptions.
+#[derive(Debug, Clone)]
+pub struct JetStreamSource {
+    pub stream: String,
+    pub durable: String,
+}
+
+impl JetStreamSource {
+    /// Consumers can only filter on one subject, so each topic gets its own durable. It is
+    /// named after the topic, even when it is the only one, for each of them to keep its
+    /// position when others are added or removed.
+    fn durable_for(&self, topic: &str) -> String {
+        // Durable names cannot hold dots, wildcards, whitespace or path separators
+        let suffix: Vec<String> = topic
+            .split('.')
+            .map(|token| match token {
+                "*" => "any".to_string(),
+                ">" => "all".to_string(),
+                token => token.replace(
+                    |c: char| c.is_whitespace() || matches!(c, '*' | '>' | '/' | '\\'),
+                    "-",
+                ),
+            })
+            .collect();
+        format!("{}_{}", self.durable, suffix.join("_"))
+    }
+}
+
+/// What `read_loop` subscribes to, and how.
+#[derive(Debug, Clone)]
+pub struct ReadOptions {
+    /// Name of the route, for logs
+    pub route: String,
+    pub topics: Vec<String>,
+    pub jetstream: Option<JetStreamSource>,
+    pub max_in_flight: usize,
+    /// Messages relayed by this process are not read back. Only set on bidirectional routes,
+    /// so one-way relays can be chained.
+    pub origin: Option<Origin>,
+    pub metrics: RouteMetrics,
+}
+
+/// Stops the handler of a subscription, core or JetStream alike.
+struct Subscription(Box<dyn FnOnce() -> Result<()> + Send>);
+
+impl Subscription {
+    fn unsubscribe(self) -> Result<()> {
+        (self.0)()
+    }
+}
+
+/// Sender shared by the subscription handlers, dropped as soon as reading stops so the next
+/// stage sees its channel disconnect once it went through what was already read.
+#[derive(Clone)]
+struct Outlet(Arc<Mutex<Option<Sender<Msg>>>>);
+
+impl Outlet {
+    fn new(write_sc: Sender<Msg>) -> Self {
+        Self(Arc::new(Mutex::new(Some(write_sc))))
+    }
+
+    /// Once the outlet is closed the message is dropped, which abandons it
+    fn send(&self, msg: Msg) {
+        if let Some(write_sc) = &*self.0.lock().unwrap() {
+            let _ = write_sc.send(msg);
+        }
+    }
+
+    fn close(&self) {
+        self.0.lock().unwrap().take();
+    }
+}
+
+/// Topics received on `topics_rc` replace the ones subscribed to, on the same connection.
 pub fn read_loop(
-    nats: String,
-    topics: Vec<String>,
-    stats_sc: Sender<u64>,
+    nc: Connection,
+    opts: ReadOptions,
+    stats_sc: Sender<Event>,
     write_sc: Sender<Msg>,
+    mut topics_rc: Receiver<Vec<String>>,
     shutdown_arc: Arc<AtomicBool>,
 ) -> Result<()> {
-    let nc = nats::connect(nats)?;
-    println!("source connected");
-
-    for topic in topics.iter() {
-        let stats = stats_sc.clone();
-        let write = write_sc.clone();
-
-        nc.subscribe(topic)?.with_handler(move |msg: Message| {
-            let _ = stats.send(msg.data.len() as u64);
-            let _ = write.send(Msg::new(msg.data, msg.subject));
+    let in_flight = InFlight::new(opts.max_in_flight);
+    let outlet = Outlet::new(write_sc);
 
-            Ok(())
-        });
-    }
+    let mut handlers = match &opts.jetstream {
+        Some(source) => subscribe_jetstream(&nc, source, &opts, &in_flight, &stats_sc, &outlet)?,
+        None => {
+            let mut handlers = BTreeMap::new();
+            for topic in opts.topics.iter() {
+                let handler = subscribe(&nc, topic, &opts, &in_flight, &stats_sc, &outlet)?;
+                handlers.insert(topic.clone(), handler);
+            }
+            handlers
+        }
+    };
 
     let pause = time::Duration::from_secs(1);
 
     while !shutdown_arc.load(Ordering::Relaxed) {
-        thread::sleep(pause);
+        match topics_rc.recv_timeout(pause) {
+            Ok(topics) => resubscribe(
+                &nc,
+                &topics,
+                &mut handlers,
+                &opts,
+                &in_flight,
+                &stats_sc,
+                &outlet,
+            ),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => topics_rc = never(),
+        }
+    }
+
+    // Nothing new comes in, what was read is left for the next stages to deliver
+    for handler in handlers.into_values() {
+        if let Err(e) = handler.unsubscribe() {
+            warn!(route = opts.route, kind = e.kind(); "cannot unsubscribe: {}", e);
+        }
     }
+    outlet.close();
 
-    eprintln!("read loop exited");
+    debug!(route = opts.route; "read loop exited");
 
     thread::sleep(pause);
 
     Ok(())
 }
+
+/// Unsubscribes from the topics no longer listed and subscribes to the new ones. Topics kept
+/// are left alone, so their messages keep flowing.
+fn resubscribe(
+    nc: &Connection,
+    topics: &[String],
+    handlers: &mut BTreeMap<String, Subscription>,
+    opts: &ReadOptions,
+    in_flight: &InFlight,
+    stats_sc: &Sender<Event>,
+    outlet: &Outlet,
+) {
+    let removed: Vec<String> = handlers
+        .keys()
+        .filter(|topic| !topics.contains(topic))
+        .cloned()
+        .collect();
+    for topic in removed {
+        if let Err(e) = handlers.remove(&topic).unwrap().unsubscribe() {
+            let kind = e.kind();
+            warn!(route = opts.route, subject = topic, kind = kind; "cannot unsubscribe: {}", e);
+        }
+    }
+
+    for topic in topics {
+        if handlers.contains_key(topic) {
+            continue;
+        }
+        match subscribe(nc, topic, opts, in_flight, stats_sc, outlet) {
+            Ok(handler) => {
+                handlers.insert(topic.clone(), handler);
+            }
+            Err(e) => {
+                let kind = e.kind();
+                error!(route = opts.route, subject = topic, kind = kind; "cannot subscribe: {}", e)
+            }
+        }
+    }
+}
+
+fn is_loop(origin: &Option<Origin>, headers: &Headers) -> bool {
+    origin
+        .as_ref()
+        .map_or(false, |origin| origin.is_loop(headers))
+}
+
+fn subscribe(
+    nc: &Connection,
+    topic: &str,
+    opts: &ReadOptions,
+    in_flight: &InFlight,
+    stats_sc: &Sender<Event>,
+    outlet: &Outlet,
+) -> Result<Subscription> {
+    let in_flight = in_flight.clone();
+    let origin = opts.origin.clone();
+    let metrics = opts.metrics.clone();
+    let stats = stats_sc.clone();
+    let outlet = outlet.clone();
+
+    let handler = nc.subscribe(topic)?.with_handler(move |msg: Message| {
+        let headers = msg
+            .headers
+            .as_ref()
+            .map(Headers::from_nats)
+            .unwrap_or_default();
+        if is_loop(&origin, &headers) {
+            return Ok(());
+        }
+
+        let ack = AckHandle::untracked().with_permit(in_flight.acquire());
+        let _ = stats.send(Event::message(
+            &msg.subject,
+            msg.data.len(),
+            Outcome::Received,
+        ));
+        metrics.received(&msg.subject, msg.data.len());
+        let msg = Msg::new(msg.data, msg.subject)
+            .with_headers(headers)
+            .with_ack(Some(ack));
+        outlet.send(msg);
+
+        Ok(())
+    });
+
+    Ok(Subscription(Box::new(move || handler.unsubscribe())))
+}
+
+fn subscribe_jetstream(
+    nc: &Connection,
+    source: &JetStreamSource,
+    opts: &ReadOptions,
+    in_flight: &InFlight,
+    stats_sc: &Sender<Event>,
+    outlet: &Outlet,
+) -> Result<BTreeMap<String, Subscription>> {
+    let js = nats::jetstream::new(nc.clone());
+    let mut handlers = BTreeMap::new();
+
+    for topic in opts.topics.iter() {
+        let in_flight = in_flight.clone();
+        let origin = opts.origin.clone();
+        let metrics = opts.metrics.clone();
+        let stats = stats_sc.clone();
+        let outlet = outlet.clone();
+        let durable = source.durable_for(topic);
+        bind_durable(nc, &js, &source.stream, &durable, topic)?;
+        let sub_opts = SubscribeOptions::bind(source.stream.clone(), durable).manual_ack();
+
+        let handler =
+            js.subscribe_with_options(topic, &sub_opts)?
+                .with_handler(move |mut msg: Message| {
+                    let headers = msg
+                        .headers
+                        .as_ref()
+                        .map(Headers::from_nats)
+                        .unwrap_or_default();
+                    if is_loop(&origin, &headers) {
+                        // Skipped on purpose, it must not be delivered again
+                        AckHandle::jetstream(msg).ack();
+                        return Ok(());
+                    }
+
+                    let permit = in_flight.acquire();
+                    let size = msg.data.len();
+                    let _ = stats.send(Event::message(&msg.subject, size, Outcome::Received));
+                    metrics.received(&msg.subject, msg.data.len());
+                    let data = std::mem::take(&mut msg.data);
+                    let topic = msg.subject.clone();
+                    // Redeliveries keep the same id, so a JetStream destination drops them
+                    let id = msg
+                        .jetstream_message_info()
+                        .map(|info| format!("{}.{}", info.stream, info.stream_seq));
+                    // Acked by the writer once the message has been published
+                    let ack = AckHandle::jetstream(msg).with_permit(permit);
+                    let msg = Msg::new(data, topic)
+                        .with_headers(headers)
+                        .with_id(id)
+                        .with_ack(Some(ack));
+                    outlet.send(msg);
+
+                    Ok(())
+                });
+        handlers.insert(
+            topic.clone(),
+            Subscription(Box::new(move || handler.unsubscribe())),
+        );
+    }
+
+    Ok(handlers)
+}
+
+/// Creates the durable consumer of a topic unless it exists. Subscriptions bound to a consumer
+/// they did not create leave it in place when they unsubscribe, so the next start picks up
+/// where this one stopped.
+fn bind_durable(
+    nc: &Connection,
+    js: &nats::jetstream::JetStream,
+    stream: &str,
+    durable: &str,
+    topic: &str,
+) -> Result<()> {
+    if js.consumer_info(stream, durable).is_ok() {
+        return Ok(());
+    }
+
+    js.add_consumer(
+        stream,
+        ConsumerConfig {
+            durable_name: Some(durable.to_string()),
+            deliver_subject: Some(nc.new_inbox()),
+            filter_subject: topic.to_string(),
+            ack_policy: AckPolicy::Explicit,
+            ..Default::default()
+        },
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JetStreamSource;
+
+    #[test]
+    fn durables_named_after_topics() {
+        let source = JetStreamSource {
+            stream: "ORDERS".to_string(),
+            durable: "naps-orders".to_string(),
+        };
+        assert_eq!(source.durable_for("orders.>"), "naps-orders_orders_all");
+        assert_eq!(
+            source.durable_for("orders.*.created"),
+            "naps-orders_orders_any_created"
+        );
+    }
+}
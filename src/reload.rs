@@ -0,0 +1,252 @@
+use crate::config::Config;
+use crate::process::Script;
+use crate::route::Route;
+use crate::{debug, error, info, warn};
+use crossbeam::channel::{never, unbounded, Receiver, Sender};
+use std::io::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{thread, time};
+
+/// Where the loops of a route receive the changes to apply.
+pub struct Updates {
+    /// Every topic the reader subscribes to
+    pub topics: Receiver<Vec<String>>,
+    /// New version of the processing script
+    pub script: Receiver<Script>,
+}
+
+/// The parts of a running route that change when the configuration is read again: the topics
+/// it subscribes to and the script processing its messages.
+///
+/// Everything else, like servers or JetStream settings, needs a restart.
+pub struct Reloadable {
+    pub name: String,
+    topics: Vec<String>,
+    script: Option<Script>,
+    topics_sc: Option<Sender<Vec<String>>>,
+    script_sc: Option<Sender<Script>>,
+}
+
+/// The script processing the messages of `route`, if any
+fn script(route: &Route) -> Option<Script> {
+    match &route.script_file {
+        Some(file) => Some(Script::File(file.clone())),
+        None if !route.script.is_empty() => Some(Script::Inline(route.script.clone())),
+        None => None,
+    }
+}
+
+impl Reloadable {
+    /// Also returns what the reader and the processing script listen to for changes. Routes
+    /// that cannot apply them get receivers that never yield anything.
+    pub fn new(route: &Route) -> (Self, Updates) {
+        // Request/reply routes have their own workers, and unsubscribing a JetStream source
+        // could remove its durable consumer
+        let (topics_sc, topics_rc) =
+            if route.request_reply.is_none() && route.source_jetstream.is_none() {
+                let (sc, rc) = unbounded();
+                (Some(sc), rc)
+            } else {
+                (None, never())
+            };
+        let script = script(route);
+        let (script_sc, script_rc) = if route.request_reply.is_none() && script.is_some() {
+            let (sc, rc) = unbounded();
+            (Some(sc), rc)
+        } else {
+            (None, never())
+        };
+
+        let reloadable = Self {
+            name: route.name.clone(),
+            topics: route.topics.clone(),
+            script,
+            topics_sc,
+            script_sc,
+        };
+
+        let updates = Updates {
+            topics: topics_rc,
+            script: script_rc,
+        };
+
+        (reloadable, updates)
+    }
+
+    /// Sends whatever changed in `route` to the loops of the running one
+    pub fn apply(&mut self, route: &Route) {
+        if route.topics != self.topics {
+            match &self.topics_sc {
+                Some(topics_sc) if topics_sc.send(route.topics.clone()).is_ok() => {
+                    info!(route = self.name; "subscribing to {:?}", route.topics);
+                    self.topics = route.topics.clone();
+                }
+                _ => warn!(route = self.name; "restart to change its topics"),
+            }
+        }
+
+        // Script files may have changed even when their path did not
+        let script = script(route);
+        if script != self.script || matches!(script, Some(Script::File(_))) {
+            match (&self.script_sc, script) {
+                (Some(sc), Some(script)) if sc.send(script.clone()).is_ok() => {
+                    info!(route = self.name; "reloading its script");
+                    self.script = Some(script);
+                }
+                _ => warn!(route = self.name; "restart to add or remove its script"),
+            }
+        }
+    }
+}
+
+/// Reads the configuration file again every time `reload_arc` is set, i.e. on SIGHUP.
+///
+/// Routes are matched by name. Routes added to or removed from the file are only reported.
+pub fn reload_loop(
+    path: Option<String>,
+    mut routes: Vec<Reloadable>,
+    reload_arc: Arc<AtomicBool>,
+    shutdown_arc: Arc<AtomicBool>,
+) -> Result<()> {
+    let pause = time::Duration::from_millis(100);
+
+    while !shutdown_arc.load(Ordering::Relaxed) {
+        thread::sleep(pause);
+        if !reload_arc.swap(false, Ordering::Relaxed) {
+            continue;
+        }
+
+        let path = match &path {
+            Some(path) => path,
+            None => {
+                warn!("nothing to reload without --config");
+                continue;
+            }
+        };
+
+        // A broken file leaves every route as it was
+        let config = match Config::load(path).and_then(Config::into_routes) {
+            Ok(config) => config,
+            Err(e) => {
+                error!(kind = e.kind(); "cannot reload config {}: {}", path, e);
+                continue;
+            }
+        };
+
+        info!("reloading config {}", path);
+        reload(&mut routes, &config);
+    }
+
+    debug!("reload loop exited");
+
+    Ok(())
+}
+
+fn reload(routes: &mut [Reloadable], config: &[Route]) {
+    for route in config {
+        match routes.iter_mut().find(|r| r.name == route.name) {
+            Some(running) => running.apply(route),
+            None => warn!(route = route.name; "restart to start it"),
+        }
+    }
+    for running in routes.iter() {
+        if !config.iter().any(|r| r.name == running.name) {
+            warn!(route = running.name; "restart to stop it");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reload, Reloadable};
+    use crate::config::Config;
+    use crate::process::Script;
+    use crate::route::Route;
+
+    fn routes(subjects: &str, script: &str) -> Vec<Route> {
+        Config::from_yaml(&format!(
+            r#"
+routes:
+  - name: orders
+    source:
+      url: nats://aws:4222
+    destination:
+      url: nats://aks:4222
+    subjects: {}
+    script: "{}"
+  - name: pricing
+    source:
+      url: nats://aws:4222
+    destination:
+      url: nats://aks:4222
+    subjects: {}
+    request_reply:
+      timeout_ms: 250
+"#,
+            subjects, script, subjects
+        ))
+        .unwrap()
+        .into_routes()
+        .unwrap()
+    }
+
+    #[test]
+    fn changes_are_sent_to_running_routes() {
+        let before = routes(r#"["orders.>"]"#, "function recv() { return true; }");
+        let (orders, orders_updates) = Reloadable::new(&before[0]);
+        let (pricing, pricing_updates) = Reloadable::new(&before[1]);
+        let mut running = vec![orders, pricing];
+
+        let after = routes(
+            r#"["orders.>", "refunds.>"]"#,
+            "function recv() { return false; }",
+        );
+        reload(&mut running, &after);
+
+        assert_eq!(
+            orders_updates.topics.try_recv().unwrap(),
+            vec!["orders.>", "refunds.>"]
+        );
+        assert_eq!(
+            orders_updates.script.try_recv().unwrap(),
+            Script::Inline("function recv() { return false; }".to_string())
+        );
+        // Request/reply routes keep their topics until restarted
+        assert!(pricing_updates.topics.try_recv().is_err());
+
+        // Nothing changed the second time
+        reload(&mut running, &after);
+        assert!(orders_updates.topics.try_recv().is_err());
+        assert!(orders_updates.script.try_recv().is_err());
+    }
+
+    #[test]
+    fn script_files_are_fetched_on_every_reload() {
+        let config = Config::from_yaml(
+            r#"
+routes:
+  - name: orders
+    source:
+      url: nats://aws:4222
+    destination:
+      url: nats://aks:4222
+    subjects: ["orders.>"]
+    script_file: ./scripts/orders.ts
+"#,
+        )
+        .unwrap()
+        .into_routes()
+        .unwrap();
+        let (orders, orders_updates) = Reloadable::new(&config[0]);
+        let mut running = vec![orders];
+
+        // Same path, but the file may have been edited
+        reload(&mut running, &config);
+        assert_eq!(
+            orders_updates.script.try_recv().unwrap(),
+            Script::File("./scripts/orders.ts".to_string())
+        );
+        assert!(orders_updates.topics.try_recv().is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use crate::conn::Side;
+use crate::mapping::Mappings;
+use crate::metrics::RouteMetrics;
+use crate::msg::Headers;
+use crate::route::Route;
+use crate::stats::{Event, Outcome};
+use crate::{debug, info, warn};
+use crossbeam::channel::{bounded, Receiver, Sender};
+use nats::{Connection, Message};
+use std::io::{ErrorKind, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{thread, time};
+
+/// Header set on the replies `naps` makes up when the destination did not answer
+pub const ERROR_HEADER: &str = "Naps-Error";
+
+/// Forward requests to the destination and relay its responses back to the requesters.
+#[derive(Debug, Clone)]
+pub struct RequestReply {
+    /// How long to wait for the destination to answer
+    pub timeout: Duration,
+    /// Requests waiting for their response at the same time
+    pub workers: usize,
+}
+
+/// Relays the requests of `route`, which must have `request_reply` set.
+///
+/// Requests are counted as read when received, and as published once answered, their latency
+/// spanning the round trip to the destination.
+pub fn reply_loop(
+    route: Route,
+    metrics: RouteMetrics,
+    stats_sc: Sender<Event>,
+    shutdown_arc: Arc<AtomicBool>,
+) -> Result<()> {
+    let Route {
+        name,
+        source,
+        target,
+        topics,
+        mappings,
+        request_reply,
+        ..
+    } = route;
+    let request_reply = request_reply.expect("not a request/reply route");
+
+    let source_nc = source.connect(Side::Source, &stats_sc, &metrics)?;
+    info!(route = name; "source connected");
+    let target_nc = target.connect(Side::Destination, &stats_sc, &metrics)?;
+    info!(route = name; "target connected");
+
+    // With the time they were read at
+    let (request_sc, request_rc) = bounded::<(Message, Instant)>(request_reply.workers);
+
+    let workers: Vec<_> = (0..request_reply.workers)
+        .map(|i| {
+            let name = name.clone();
+            let source_nc = source_nc.clone();
+            let target_nc = target_nc.clone();
+            let request_rc = request_rc.clone();
+            let mappings = mappings.clone();
+            let metrics = metrics.clone();
+            let stats_sc = stats_sc.clone();
+            let timeout = request_reply.timeout;
+            thread::Builder::new()
+                .name(format!("reply-{}", i))
+                .spawn(move || {
+                    reply_worker(
+                        name,
+                        (source_nc, target_nc),
+                        request_rc,
+                        timeout,
+                        mappings,
+                        metrics,
+                        stats_sc,
+                    )
+                })
+                .unwrap()
+        })
+        .collect();
+
+    for topic in topics.iter() {
+        let request = request_sc.clone();
+        let metrics = metrics.clone();
+        let stats = stats_sc.clone();
+
+        source_nc
+            .subscribe(topic)?
+            .with_handler(move |msg: Message| {
+                let size = msg.data.len();
+                let _ = stats.send(Event::message(&msg.subject, size, Outcome::Received));
+                metrics.received(&msg.subject, size);
+                let _ = request.send((msg, Instant::now()));
+
+                Ok(())
+            });
+    }
+
+    let pause = time::Duration::from_secs(1);
+
+    while !shutdown_arc.load(Ordering::Relaxed) {
+        thread::sleep(pause);
+    }
+
+    source_nc.close();
+    drop(request_sc);
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    debug!(route = name; "reply loop exited");
+
+    Ok(())
+}
+
+fn reply_worker(
+    route: String,
+    (source_nc, target_nc): (Connection, Connection),
+    request_rc: Receiver<(Message, Instant)>,
+    timeout: Duration,
+    mappings: Mappings,
+    metrics: RouteMetrics,
+    stats_sc: Sender<Event>,
+) {
+    for (request, read_at) in request_rc.iter() {
+        let subject = mappings
+            .map(&request.subject)
+            .unwrap_or_else(|| request.subject.clone());
+
+        let reply = match &request.reply {
+            Some(reply) => reply,
+            // Nobody waits for an answer, just relay it
+            None => {
+                let publish = target_nc.publish_with_reply_or_headers(
+                    &subject,
+                    None,
+                    request.headers.as_ref(),
+                    &request.data,
+                );
+                let size = request.data.len();
+                let outcome = match publish {
+                    Ok(()) => {
+                        metrics.published(&subject, size, read_at);
+                        Outcome::Published
+                    }
+                    Err(e) => {
+                        warn!(
+                            route = route,
+                            subject = subject,
+                            kind = e.kind();
+                            "cannot publish: {}",
+                            e
+                        );
+                        metrics.publish_error(&subject);
+                        Outcome::Failed
+                    }
+                };
+                let _ = stats_sc.send(Event::message(&subject, size, outcome));
+                continue;
+            }
+        };
+
+        let response = target_nc.request_with_headers_or_timeout(
+            &subject,
+            request.headers.as_ref(),
+            Some(timeout),
+            &request.data,
+        );
+
+        let relayed = match response {
+            Ok(response) => {
+                let size = request.data.len();
+                let _ = stats_sc.send(Event::message(&subject, size, Outcome::Published));
+                metrics.published(&subject, size, read_at);
+                source_nc.publish_with_reply_or_headers(
+                    reply,
+                    None,
+                    response.headers.as_ref(),
+                    &response.data,
+                )
+            }
+            Err(e) => {
+                let size = request.data.len();
+                let _ = stats_sc.send(Event::message(&subject, size, Outcome::Failed));
+                metrics.publish_error(&subject);
+                let reason = match e.kind() {
+                    ErrorKind::NotFound => "no responders",
+                    ErrorKind::TimedOut => "timeout",
+                    _ => "request failed",
+                };
+                warn!(route = route, subject = subject, kind = reason; "request failed: {}", e);
+                // Let the requester fail fast instead of waiting for its own timeout
+                let mut error = Headers::new();
+                error.insert(ERROR_HEADER, reason);
+                source_nc.publish_with_reply_or_headers(reply, None, error.to_nats().as_ref(), b"")
+            }
+        };
+
+        if let Err(e) = relayed {
+            warn!(
+                route = route,
+                subject = reply,
+                kind = e.kind();
+                "cannot relay response: {}",
+                e
+            );
+        }
+    }
+}
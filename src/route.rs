@@ -0,0 +1,107 @@
+use crate::conn::{ConnectOptions, Side};
+use crate::dead_letter::DeadLetterTarget;
+use crate::mapping::{self, Mappings};
+use crate::read::JetStreamSource;
+use crate::reply::RequestReply;
+use crate::spill::SpillOptions;
+use crate::write::JetStreamTarget;
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+/// One relay pipeline: what to read, where from and where to write it.
+#[derive(Debug)]
+pub struct Route {
+    pub name: String,
+    pub source: ConnectOptions,
+    pub target: ConnectOptions,
+    pub topics: Vec<String>,
+    /// Topics relayed the other way around, from the target to the source
+    pub reverse_topics: Vec<String>,
+    /// Subject renames applied right before publishing
+    pub mappings: Mappings,
+    pub source_jetstream: Option<JetStreamSource>,
+    pub target_jetstream: Option<JetStreamTarget>,
+    pub max_in_flight: usize,
+    /// Store messages on disk between reading and writing them
+    pub spill: Option<SpillOptions>,
+    /// Proxy requests and their responses instead of relaying messages one way
+    pub request_reply: Option<RequestReply>,
+    pub script: String,
+    /// Path or URL of a module to run as the script, instead of `script`
+    pub script_file: Option<String>,
+    /// How long the promise returned by `recv` may take, zero for no limit
+    pub script_timeout: Duration,
+    /// Messages whose `recv` promise is pending at the same time
+    pub script_concurrency: usize,
+    /// Where messages the script threw on or the destination refused end up
+    pub dead_letter: Option<DeadLetterTarget>,
+}
+
+impl Route {
+    /// Relays topics both ways, which is when relayed messages are stamped to avoid loops
+    pub fn is_bidirectional(&self) -> bool {
+        !self.reverse_topics.is_empty()
+    }
+
+    pub fn has_script(&self) -> bool {
+        return !self.script.is_empty() || self.script_file.is_some();
+    }
+
+    /// Rejects settings that cannot work together, wherever the route was defined
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: String| {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("route '{}' {}", self.name, reason),
+            ))
+        };
+
+        if matches!(&self.target_jetstream, Some(js) if js.max_in_flight == 0) {
+            // Nothing could ever be published
+            return invalid("needs a JetStream max in flight of at least 1".to_string());
+        }
+
+        if self.request_reply.is_some() {
+            // Requests skip the relay pipeline, none of these would apply
+            let ignored = [
+                (self.has_script(), "a script"),
+                (self.source_jetstream.is_some(), "a JetStream source"),
+                (self.target_jetstream.is_some(), "a JetStream destination"),
+                (self.is_bidirectional(), "reverse subjects"),
+                (self.dead_letter.is_some(), "a dead letter destination"),
+            ];
+            if let Some((_, setting)) = ignored.iter().find(|(set, _)| *set) {
+                return invalid(format!("relays requests and cannot have {}", setting));
+            }
+        }
+
+        if matches!(&self.request_reply, Some(rr) if rr.workers == 0) {
+            // Requests would never be forwarded
+            return invalid("needs at least 1 request worker".to_string());
+        }
+
+        let tls = [
+            (Side::Source, &self.source.tls),
+            (Side::Destination, &self.target.tls),
+        ];
+        for (side, tls) in tls {
+            if tls.cert.is_some() != tls.key.is_some() {
+                return invalid(format!(
+                    "needs both a tls client certificate and key on its {}",
+                    side
+                ));
+            }
+        }
+
+        if let Some(DeadLetterTarget::Source(subject)) = &self.dead_letter {
+            if let Some(topic) = self.topics.iter().find(|t| mapping::matches(t, subject)) {
+                return invalid(format!(
+                    "reads its own dead letters, {} matches {}",
+                    subject, topic
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Graceful stop of the pipeline, shared by every loop downstream of the readers.
+///
+/// Once shutdown is requested readers stop, and the next stages keep going until their input
+/// channel is empty and disconnected, so what was already read still gets delivered. Past the
+/// deadline the drain expires and the loops drop whatever is left.
+///
+/// Messages settled while draining are tallied, to report how many made it out.
+///
+/// # Example
+///
+/// ```rust
+/// use naps::shutdown::Drain;
+/// use std::sync::atomic::{AtomicBool, Ordering};
+/// use std::sync::Arc;
+///
+/// let requested = Arc::new(AtomicBool::new(false));
+/// let drain = Drain::new(Arc::clone(&requested));
+/// drain.flushed(3);
+/// assert_eq!(drain.to_string(), "0 messages flushed, 0 dropped");
+///
+/// requested.store(true, Ordering::Relaxed);
+/// drain.flushed(3);
+/// drain.dropped(1);
+/// assert_eq!(drain.to_string(), "3 messages flushed, 1 dropped");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Drain {
+    requested: Arc<AtomicBool>,
+    expired: Arc<AtomicBool>,
+    flushed: Arc<AtomicU64>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Drain {
+    /// `requested` is the flag set by the signal handlers
+    pub fn new(requested: Arc<AtomicBool>) -> Self {
+        Self {
+            requested,
+            expired: Arc::new(AtomicBool::new(false)),
+            flushed: Arc::new(AtomicU64::new(0)),
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    /// The deadline passed, loops must stop waiting for anything
+    pub fn is_expired(&self) -> bool {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    pub fn expire(&self) {
+        self.expired.store(true, Ordering::Relaxed);
+    }
+
+    /// Counts messages delivered, only once shutdown started
+    pub fn flushed(&self, count: usize) {
+        if self.is_draining() {
+            self.flushed.fetch_add(count as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts messages given up on, only once shutdown started
+    pub fn dropped(&self, count: usize) {
+        if self.is_draining() {
+            self.dropped.fetch_add(count as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Display for Drain {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} messages flushed, {} dropped",
+            self.flushed.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed)
+        )
+    }
+}
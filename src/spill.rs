@@ -0,0 +1,902 @@
+use crate::ack::{AckHandle, Acknowledge};
+use crate::msg::{Headers, Msg};
+use crate::shutdown::Drain;
+use crate::{debug, error, warn};
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How long to wait for messages or free space before checking for shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The read position is saved at most this often, a crash replays what was acked since
+const CURSOR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long an abandoned message waits before it is popped again, doubled on each attempt
+const ABANDON_DELAY: Duration = Duration::from_millis(100);
+
+/// Pops of a message ending abandoned before it is given up on, as if delivered
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Messages appended between two syncs to disk, their sources are acked after each sync
+const SYNC_BATCH: usize = 256;
+
+const SEGMENT_EXTENSION: &str = "seg";
+const CURSOR_FILE: &str = "cursor";
+
+/// Where and how much to spill to disk.
+#[derive(Debug, Clone)]
+pub struct SpillOptions {
+    pub dir: PathBuf,
+    /// Bytes on disk before appending blocks
+    pub max_bytes: u64,
+    /// Bytes per segment file, segments are deleted once every message in them was delivered
+    pub segment_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Position {
+    segment: u64,
+    offset: u64,
+}
+
+/// Write-ahead queue of messages stored in segment files, so the reader never waits for the
+/// writer and nothing is lost when `naps` restarts.
+///
+/// Messages popped from the queue carry an ack handle. Acked messages are dropped from disk,
+/// nacked ones are popped again, and the ones neither acked nor nacked before a restart are
+/// replayed. Abandoned ones are popped again after a growing delay, and given up on after
+/// [`MAX_ATTEMPTS`], for a message failing every time not to hold the queue forever.
+#[derive(Clone)]
+pub struct SpillQueue {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    /// Length of every segment file, by id
+    segments: BTreeMap<u64, u64>,
+    /// Appends go to the last segment
+    writer: File,
+    reader: Option<(u64, File)>,
+    /// Next message popped for the first time
+    read: Position,
+    /// Popped and not acked yet, by sequence number, with whether they were acked since
+    pending: BTreeMap<u64, (Position, bool)>,
+    /// Nacked messages, popped again before anything else
+    retry: VecDeque<u64>,
+    /// Abandoned messages, by sequence number, with when they can be popped again
+    delayed: BTreeMap<u64, Instant>,
+    /// How many times each popped message was abandoned so far
+    abandoned: BTreeMap<u64, u32>,
+    next_seq: u64,
+    /// Everything before it has been delivered
+    cursor: Position,
+    cursor_saved_at: Instant,
+    closed: bool,
+}
+
+impl SpillQueue {
+    /// Opens the queue in `dir`, picking up whatever was left undelivered by a previous run
+    pub fn open(opts: &SpillOptions) -> Result<Self> {
+        let dir = opts.dir.clone();
+        fs::create_dir_all(&dir)?;
+
+        let mut segments = BTreeMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                segments.insert(id, fs::metadata(&path)?.len());
+            }
+        }
+
+        let first = segments.keys().next().copied().unwrap_or(0);
+        let cursor = match load_cursor(&dir)? {
+            Some(cursor) if segments.contains_key(&cursor.segment) => cursor,
+            _ => Position {
+                segment: first,
+                offset: 0,
+            },
+        };
+
+        // Delivered before the previous run stopped
+        let delivered: Vec<u64> = segments
+            .range(..cursor.segment)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in delivered {
+            fs::remove_file(segment_path(&dir, id))?;
+            segments.remove(&id);
+        }
+        if segments.is_empty() {
+            File::create(segment_path(&dir, cursor.segment))?;
+            segments.insert(cursor.segment, 0);
+        }
+
+        // A crash can leave half a message at the end of the last segment
+        let (&last, &len) = segments.iter().next_back().unwrap();
+        let valid = valid_len(&segment_path(&dir, last))?;
+        if valid < len {
+            warn!(
+                kind = "corrupted";
+                "dropping {} bytes of a partial message in the spill queue",
+                len - valid
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(segment_path(&dir, last))?
+                .set_len(valid)?;
+            segments.insert(last, valid);
+        }
+        let cursor = Position {
+            segment: cursor.segment,
+            offset: cursor.offset.min(segments[&cursor.segment]),
+        };
+
+        let writer = OpenOptions::new()
+            .append(true)
+            .open(segment_path(&dir, last))?;
+
+        let state = State {
+            dir,
+            max_bytes: opts.max_bytes,
+            // Space is only freed a whole segment at a time, a full queue needs several of them
+            segment_bytes: opts.segment_bytes.min(opts.max_bytes / 2).max(1),
+            segments,
+            writer,
+            reader: None,
+            read: cursor,
+            pending: BTreeMap::new(),
+            retry: VecDeque::new(),
+            delayed: BTreeMap::new(),
+            abandoned: BTreeMap::new(),
+            next_seq: 0,
+            cursor,
+            cursor_saved_at: Instant::now(),
+            closed: false,
+        };
+
+        Ok(Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(state),
+                changed: Condvar::new(),
+            }),
+        })
+    }
+
+    /// Appends the message, waiting up to `timeout` for room if the queue is full.
+    ///
+    /// The message is only durable once `sync` returned.
+    pub fn push(&self, msg: &Msg, timeout: Duration) -> Result<()> {
+        let record = encode(msg);
+        let len = record.len() as u64;
+        let deadline = Instant::now() + timeout;
+
+        let mut state = self.lock();
+        if len > state.max_bytes {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} bytes message does not fit in the spill queue", len),
+            ));
+        }
+        while state.size() + len > state.max_bytes && !state.closed {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::new(ErrorKind::TimedOut, "spill queue full"));
+            }
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+        if state.closed {
+            return Err(Error::new(ErrorKind::BrokenPipe, "spill queue closed"));
+        }
+
+        state.append(&record)?;
+        self.shared.changed.notify_all();
+
+        Ok(())
+    }
+
+    /// Next message to deliver, `None` if nothing came in before the timeout
+    pub fn pop(&self, timeout: Duration) -> Result<Option<Msg>> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+
+        loop {
+            if let Some((seq, msg)) = state.next()? {
+                let ack = AckHandle::new(Box::new(SpillAck {
+                    queue: self.clone(),
+                    seq,
+                }));
+                return Ok(Some(msg.with_ack(Some(ack))));
+            }
+
+            let now = Instant::now();
+            if state.closed || now >= deadline {
+                return Ok(None);
+            }
+            let wake = match state.delayed.values().min() {
+                Some(&at) => at.clamp(now, deadline),
+                None => deadline,
+            };
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, wake - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Flushes the appended messages to disk
+    pub fn sync(&self) -> Result<()> {
+        self.lock().writer.sync_data()
+    }
+
+    /// Bytes on disk, delivered messages included until their whole segment is
+    pub fn size(&self) -> u64 {
+        self.lock().size()
+    }
+
+    /// Wakes up whoever waits on the queue and saves the read position
+    pub fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+        if let Err(e) = state.save_cursor() {
+            error!(kind = e.kind(); "cannot save spill queue position: {}", e);
+        }
+        self.shared.changed.notify_all();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().unwrap()
+    }
+
+    fn ack(&self, seq: u64) -> Result<()> {
+        let mut state = self.lock();
+        if let Some(entry) = state.pending.get_mut(&seq) {
+            entry.1 = true;
+        }
+        state.abandoned.remove(&seq);
+        state.commit()?;
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    fn abandon(&self, seq: u64) -> Result<()> {
+        let mut state = self.lock();
+        if !state.pending.contains_key(&seq) {
+            return Ok(());
+        }
+
+        let attempts = state.abandoned.entry(seq).or_default();
+        *attempts += 1;
+        let attempts = *attempts;
+        if attempts < MAX_ATTEMPTS {
+            let delay = ABANDON_DELAY * 2_u32.pow(attempts - 1);
+            state.delayed.insert(seq, Instant::now() + delay);
+            return Ok(());
+        }
+
+        warn!(
+            kind = "dropped";
+            "giving up on spilled message #{} after {} attempts",
+            seq,
+            attempts
+        );
+        state.abandoned.remove(&seq);
+        if let Some(entry) = state.pending.get_mut(&seq) {
+            entry.1 = true;
+        }
+        state.commit()?;
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    fn nack(&self, seq: u64) {
+        let mut state = self.lock();
+        if state.pending.contains_key(&seq) {
+            state.retry.push_back(seq);
+            self.shared.changed.notify_all();
+        }
+    }
+}
+
+impl State {
+    fn size(&self) -> u64 {
+        self.segments.values().sum()
+    }
+
+    fn last_segment(&self) -> u64 {
+        *self.segments.keys().next_back().unwrap()
+    }
+
+    fn append(&mut self, record: &[u8]) -> Result<()> {
+        let last = self.last_segment();
+        if self.segments[&last] >= self.segment_bytes {
+            let id = last + 1;
+            // Only the last segment is synced by `SpillQueue::sync`
+            self.writer.sync_data()?;
+            self.writer = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(segment_path(&self.dir, id))?;
+            File::open(&self.dir)?.sync_all()?;
+            self.segments.insert(id, 0);
+        }
+
+        self.writer.write_all(record)?;
+        let last = self.last_segment();
+        *self.segments.get_mut(&last).unwrap() += record.len() as u64;
+
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<(u64, Msg)>> {
+        let now = Instant::now();
+        let due: Vec<u64> = self
+            .delayed
+            .iter()
+            .filter(|(_, &at)| at <= now)
+            .map(|(&seq, _)| seq)
+            .collect();
+        for seq in due {
+            self.delayed.remove(&seq);
+            self.retry.push_back(seq);
+        }
+
+        while let Some(seq) = self.retry.pop_front() {
+            if let Some(&(position, _)) = self.pending.get(&seq) {
+                return match self.read_at(position) {
+                    Ok((msg, _)) => Ok(Some((seq, msg))),
+                    // Given up on, as if delivered
+                    Err(e) => {
+                        self.abandoned.remove(&seq);
+                        self.pending.insert(seq, (position, true));
+                        self.commit()?;
+                        Err(e)
+                    }
+                };
+            }
+        }
+
+        while self.read.offset >= self.segments[&self.read.segment] {
+            match self.segments.range(self.read.segment + 1..).next() {
+                Some((&id, _)) => {
+                    self.read = Position {
+                        segment: id,
+                        offset: 0,
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+
+        let position = self.read;
+        let (msg, next) = match self.read_at(position) {
+            Ok(read) => read,
+            // Records are only found from the previous one, the rest of the segment is lost
+            Err(e) => {
+                self.read.offset = self.segments[&position.segment];
+                self.commit()?;
+                return Err(Error::new(
+                    e.kind(),
+                    format!(
+                        "skipped the rest of spill segment {} from offset {}: {}",
+                        position.segment, position.offset, e
+                    ),
+                ));
+            }
+        };
+        self.read.offset = next;
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.pending.insert(seq, (position, false));
+
+        Ok(Some((seq, msg)))
+    }
+
+    fn read_at(&mut self, position: Position) -> Result<(Msg, u64)> {
+        if self.reader.as_ref().map(|(segment, _)| *segment) != Some(position.segment) {
+            let file = File::open(segment_path(&self.dir, position.segment))?;
+            self.reader = Some((position.segment, file));
+        }
+        let file = &mut self.reader.as_mut().unwrap().1;
+
+        file.seek(SeekFrom::Start(position.offset))?;
+        let mut len = [0; 4];
+        file.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len);
+        let mut payload = vec![0; len as usize];
+        file.read_exact(&mut payload)?;
+
+        Ok((decode(&payload)?, position.offset + 4 + len as u64))
+    }
+
+    /// Moves the cursor past the messages acked in a row and deletes the segments behind it
+    fn commit(&mut self) -> Result<()> {
+        while let Some((&seq, &(_, true))) = self.pending.iter().next() {
+            self.pending.remove(&seq);
+        }
+
+        let cursor = match self.pending.values().next() {
+            Some(&(position, _)) => position,
+            None => self.read,
+        };
+        if cursor == self.cursor {
+            return Ok(());
+        }
+        self.cursor = cursor;
+
+        let delivered: Vec<u64> = self
+            .segments
+            .range(..cursor.segment)
+            .map(|(&id, _)| id)
+            .collect();
+        if !delivered.is_empty() {
+            // Saved first, so a crash in between never points to a deleted segment
+            self.save_cursor()?;
+            for id in delivered {
+                fs::remove_file(segment_path(&self.dir, id))?;
+                self.segments.remove(&id);
+            }
+        } else if self.cursor_saved_at.elapsed() >= CURSOR_INTERVAL {
+            self.save_cursor()?;
+        }
+
+        Ok(())
+    }
+
+    fn save_cursor(&mut self) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", CURSOR_FILE));
+        fs::write(
+            &tmp,
+            format!("{} {}\n", self.cursor.segment, self.cursor.offset),
+        )?;
+        fs::rename(&tmp, self.dir.join(CURSOR_FILE))?;
+        self.cursor_saved_at = Instant::now();
+        Ok(())
+    }
+}
+
+impl Drop for State {
+    /// Acks arriving after `close` still count
+    fn drop(&mut self) {
+        if let Err(e) = self.save_cursor() {
+            error!(kind = e.kind(); "cannot save spill queue position: {}", e);
+        }
+    }
+}
+
+struct SpillAck {
+    queue: SpillQueue,
+    seq: u64,
+}
+
+impl Acknowledge for SpillAck {
+    fn ack(&mut self) -> Result<()> {
+        self.queue.ack(self.seq)
+    }
+
+    fn nack(&mut self) -> Result<()> {
+        self.queue.nack(self.seq);
+        Ok(())
+    }
+
+    fn abandon(&mut self) -> Result<()> {
+        self.queue.abandon(self.seq)
+    }
+
+    fn describe(&self) -> String {
+        format!("spill queue #{}", self.seq)
+    }
+}
+
+/// Appends what the reader sends to the queue, acking each message to its source once synced
+/// to disk.
+///
+/// On shutdown everything the reader already sent is stored before the queue is closed.
+pub fn spill_loop(queue: SpillQueue, msg_rc: &Receiver<Msg>, drain: Drain) -> Result<()> {
+    let mut stored = Vec::with_capacity(SYNC_BATCH);
+    while !drain.is_expired() {
+        let msg = match msg_rc.recv_timeout(POLL_INTERVAL) {
+            Ok(msg) => msg,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        store(&queue, msg, &drain, &mut stored);
+        while stored.len() < SYNC_BATCH {
+            match msg_rc.try_recv() {
+                Ok(msg) => store(&queue, msg, &drain, &mut stored),
+                Err(_) => break,
+            }
+        }
+
+        match queue.sync() {
+            Ok(()) => {
+                drain.flushed(stored.len());
+                stored.drain(..).for_each(|mut msg| msg.ack());
+            }
+            // Abandoned on drop, they might be delivered twice
+            Err(e) => {
+                error!(kind = e.kind(); "cannot sync spill queue: {}", e);
+                drain.dropped(stored.len());
+                stored.clear();
+            }
+        }
+    }
+    drain.dropped(msg_rc.len());
+
+    queue.close();
+
+    debug!("spill loop exited");
+
+    Ok(())
+}
+
+/// Appends the message, keeping it in `stored` until the queue is synced
+fn store(queue: &SpillQueue, msg: Msg, drain: &Drain, stored: &mut Vec<Msg>) {
+    loop {
+        match queue.push(&msg, POLL_INTERVAL) {
+            Ok(()) => stored.push(msg),
+            Err(e) if e.kind() == ErrorKind::TimedOut && !drain.is_expired() => continue,
+            // Nacked on drop
+            Err(e) => {
+                error!(subject = msg, kind = e.kind(); "cannot spill message: {}", e);
+                drain.dropped(1);
+            }
+        }
+        return;
+    }
+}
+
+/// Feeds the next stage with the messages stored in the queue, oldest first.
+///
+/// Stops on shutdown, what is still stored is delivered on the next start.
+pub fn drain_loop(
+    queue: SpillQueue,
+    msg_sc: Sender<Msg>,
+    shutdown_arc: Arc<AtomicBool>,
+) -> Result<()> {
+    while !shutdown_arc.load(Ordering::Relaxed) {
+        match queue.pop(POLL_INTERVAL) {
+            Ok(Some(msg)) => {
+                if msg_sc.send(msg).is_err() {
+                    break;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => error!(kind = e.kind(); "cannot read spilled message: {}", e),
+        }
+    }
+
+    debug!("drain loop exited");
+
+    Ok(())
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, SEGMENT_EXTENSION))
+}
+
+fn load_cursor(dir: &Path) -> Result<Option<Position>> {
+    let content = match fs::read_to_string(dir.join(CURSOR_FILE)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut parts = content.split_whitespace().map(str::parse::<u64>);
+    match (parts.next(), parts.next()) {
+        (Some(Ok(segment)), Some(Ok(offset))) => Ok(Some(Position { segment, offset })),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("corrupted spill queue cursor in {}", dir.display()),
+        )),
+    }
+}
+
+/// Length of the segment up to its last complete message
+fn valid_len(path: &Path) -> Result<u64> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut offset = 0;
+
+    loop {
+        let mut len = [0; 4];
+        if offset + 4 > file_len || file.read_exact(&mut len).is_err() {
+            return Ok(offset);
+        }
+        let end = offset + 4 + u32::from_le_bytes(len) as u64;
+        if end > file_len {
+            return Ok(offset);
+        }
+        offset = file.seek(SeekFrom::Start(end))?;
+    }
+}
+
+/// Length-prefixed message: topic, id, headers and data, each of them length-prefixed too
+fn encode(msg: &Msg) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(msg.data.len() + msg.topic.len() + 64);
+    put(&mut payload, msg.topic.as_bytes());
+    put(
+        &mut payload,
+        msg.id.as_deref().unwrap_or_default().as_bytes(),
+    );
+
+    let headers: Vec<(&String, &String)> = msg
+        .headers
+        .iter()
+        .flat_map(|(name, values)| values.iter().map(move |value| (name, value)))
+        .collect();
+    payload.extend_from_slice(&(headers.len() as u32).to_le_bytes());
+    for (name, value) in headers {
+        put(&mut payload, name.as_bytes());
+        put(&mut payload, value.as_bytes());
+    }
+
+    put(&mut payload, &msg.data);
+
+    let mut record = Vec::with_capacity(payload.len() + 4);
+    put(&mut record, &payload);
+    record
+}
+
+fn put(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn decode(payload: &[u8]) -> Result<Msg> {
+    let mut record = Record { buf: payload };
+
+    let topic = record.string()?;
+    let id = Some(record.string()?).filter(|id| !id.is_empty());
+    let count = record.u32()?;
+    let mut headers = Headers::new();
+    for _ in 0..count {
+        let name = record.string()?;
+        headers.append(&name, &record.string()?);
+    }
+    let data = record.bytes()?.to_vec();
+
+    Ok(Msg::new(data, topic).with_headers(headers).with_id(id))
+}
+
+struct Record<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    fn u32(&mut self) -> Result<u32> {
+        if self.buf.len() < 4 {
+            return Err(corrupted());
+        }
+        let (value, rest) = self.buf.split_at(4);
+        self.buf = rest;
+        Ok(u32::from_le_bytes(value.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        if self.buf.len() < len {
+            return Err(corrupted());
+        }
+        let (value, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(value)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| corrupted())
+    }
+}
+
+fn corrupted() -> Error {
+    Error::new(ErrorKind::InvalidData, "corrupted spill queue record")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, SpillOptions, SpillQueue, MAX_ATTEMPTS};
+    use crate::msg::{Headers, Msg};
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::time::Duration;
+
+    fn options(dir: &std::path::Path) -> SpillOptions {
+        SpillOptions {
+            dir: dir.to_path_buf(),
+            max_bytes: 1024 * 1024,
+            segment_bytes: 64,
+        }
+    }
+
+    fn msg(data: &str) -> Msg {
+        Msg::from_str(data.to_string(), "orders.created".to_string())
+    }
+
+    const TIMEOUT: Duration = Duration::from_millis(10);
+
+    fn pop(queue: &SpillQueue) -> Msg {
+        queue.pop(TIMEOUT).unwrap().unwrap()
+    }
+
+    #[test]
+    fn record_roundtrip() {
+        let mut headers = Headers::new();
+        headers.append("Trace-Id", "a");
+        headers.append("Trace-Id", "b");
+        let original = Msg::new(vec![0, 159, 146, 150], "orders.created".to_string())
+            .with_headers(headers.clone())
+            .with_id(Some("ORDERS.7".to_string()));
+
+        let record = encode(&original);
+        let decoded = decode(&record[4..]).unwrap();
+        assert_eq!(decoded.data, original.data);
+        assert_eq!(decoded.topic, original.topic);
+        assert_eq!(decoded.headers, headers);
+        assert_eq!(decoded.id.as_deref(), Some("ORDERS.7"));
+
+        assert!(decode(&record[4..record.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn acked_messages_are_not_replayed() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let queue = SpillQueue::open(&options(dir.path())).unwrap();
+        for i in 0..10 {
+            queue
+                .push(&msg(&format!("message {}", i)), TIMEOUT)
+                .unwrap();
+        }
+        for i in 0..4 {
+            let mut popped = pop(&queue);
+            assert_eq!(popped.data, format!("message {}", i).into_bytes());
+            popped.ack();
+        }
+        // Popped but never delivered
+        drop(pop(&queue));
+        queue.close();
+        drop(queue);
+
+        let queue = SpillQueue::open(&options(dir.path())).unwrap();
+        for i in 4..10 {
+            let mut popped = pop(&queue);
+            assert_eq!(popped.data, format!("message {}", i).into_bytes());
+            popped.ack();
+        }
+        assert!(queue.pop(TIMEOUT).unwrap().is_none());
+    }
+
+    #[test]
+    fn nacked_messages_are_popped_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = SpillQueue::open(&options(dir.path())).unwrap();
+        queue.push(&msg("first"), TIMEOUT).unwrap();
+        queue.push(&msg("second"), TIMEOUT).unwrap();
+
+        pop(&queue).nack();
+        assert_eq!(pop(&queue).data, b"first");
+    }
+
+    #[test]
+    fn abandoned_messages_are_given_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = SpillQueue::open(&options(dir.path())).unwrap();
+        queue.push(&msg("poison"), TIMEOUT).unwrap();
+        queue.push(&msg("fine"), TIMEOUT).unwrap();
+
+        // Dropped, e.g. the script throws on it every time
+        drop(pop(&queue));
+        // Not popped again right away
+        let mut fine = pop(&queue);
+        assert_eq!(fine.data, b"fine");
+        fine.ack();
+
+        for _ in 1..MAX_ATTEMPTS {
+            let poison = queue.pop(Duration::from_secs(5)).unwrap().unwrap();
+            assert_eq!(poison.data, b"poison");
+        }
+        assert!(queue.pop(Duration::from_secs(2)).unwrap().is_none());
+        queue.close();
+        drop(queue);
+
+        // Given up on as if delivered, so not replayed either
+        let queue = SpillQueue::open(&options(dir.path())).unwrap();
+        assert!(queue.pop(TIMEOUT).unwrap().is_none());
+    }
+
+    #[test]
+    fn delivered_segments_are_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = SpillQueue::open(&options(dir.path())).unwrap();
+        for i in 0..10 {
+            queue
+                .push(&msg(&format!("message {}", i)), TIMEOUT)
+                .unwrap();
+        }
+        let full = queue.size();
+
+        for _ in 0..10 {
+            pop(&queue).ack();
+        }
+        assert!(queue.size() < full);
+    }
+
+    #[test]
+    fn corrupt_records_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = SpillQueue::open(&options(dir.path())).unwrap();
+        // The first two share a segment, the third one starts the next
+        for data in ["first", "second", "third"] {
+            queue.push(&msg(data), TIMEOUT).unwrap();
+        }
+        queue.sync().unwrap();
+
+        let first = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("seg"))
+            .min()
+            .unwrap();
+        let mut file = OpenOptions::new().write(true).open(first).unwrap();
+        // Topic length of the first record
+        file.seek(SeekFrom::Start(4)).unwrap();
+        file.write_all(&[255, 255, 255, 255]).unwrap();
+
+        assert!(queue.pop(TIMEOUT).is_err());
+        let mut popped = pop(&queue);
+        assert_eq!(popped.data, b"third");
+        popped.ack();
+        assert!(queue.pop(TIMEOUT).unwrap().is_none());
+    }
+
+    #[test]
+    fn partial_records_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = SpillQueue::open(&options(dir.path())).unwrap();
+        queue.push(&msg("complete"), TIMEOUT).unwrap();
+        queue.close();
+        drop(queue);
+
+        let segment = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().and_then(|ext| ext.to_str()) == Some("seg"))
+            .unwrap();
+        let mut file = OpenOptions::new().append(true).open(segment).unwrap();
+        file.write_all(&[200, 0, 0, 0, 1, 2]).unwrap();
+
+        let queue = SpillQueue::open(&options(dir.path())).unwrap();
+        let mut popped = pop(&queue);
+        assert_eq!(popped.data, b"complete");
+        popped.ack();
+        assert!(queue.pop(TIMEOUT).unwrap().is_none());
+    }
+}
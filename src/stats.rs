@@ -1,67 +1,367 @@
-use crossbeam::channel::{select, Receiver};
+use crossbeam::channel::{Receiver, RecvTimeoutError};
 use crossterm::{
     cursor, execute,
     style::{self, Color, PrintStyledContent, Stylize},
     terminal::{Clear, ClearType},
 };
 
+use std::collections::BTreeMap;
 use std::io::{self, Result, Stderr, Write};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
-use std::u64;
+use std::time::{Duration, Instant};
 
+use crate::conn::Side;
+use crate::dashboard::Dashboard;
+use crate::metrics::{Metrics, MAX_SUBJECTS, OTHER_SUBJECT};
 use crate::timer::Timer;
+use crate::{debug, info, warn};
 
+/// How long to wait for events before checking for shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often rates are computed and the progress line or the dashboard redrawn
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Windows rates are averaged over, shortest first
+pub const RATE_WINDOWS: [Duration; 3] = [
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+    Duration::from_secs(60),
+];
+
+/// What became of a message at one stage of the pipeline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// Read from the source
+    Received,
+    /// Confirmed by the destination
+    Published,
+    /// Rejected by the script, or could not be published to the destination
+    Failed,
+}
+
+/// Something worth counting that happened in one of the loops.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A message went through a stage, `subject` being the one it had there
+    Message {
+        subject: String,
+        size: u64,
+        outcome: Outcome,
+    },
+    /// The connection to one side dropped, `nats` is trying to get it back
+    Disconnected(Side),
+    Reconnected(Side),
+}
+
+impl Event {
+    pub fn message(subject: &str, size: usize, outcome: Outcome) -> Self {
+        Self::Message {
+            subject: subject.to_string(),
+            size: size as u64,
+            outcome,
+        }
+    }
+}
+
+/// How the stats loop shows progress.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum View {
+    Quiet,
+    /// A single line of totals, updated in place
+    Progress,
+    /// Full-screen table of the busiest subjects
+    Dashboard,
+}
+
+/// Per-second rate of something counted, as exponentially weighted moving averages over each
+/// of [`RATE_WINDOWS`], like load averages.
+///
+/// # Example
+///
+/// ```rust
+/// use naps::stats::Rate;
+/// use std::time::Duration;
+///
+/// let mut rate = Rate::default();
+/// rate.add(300);
+/// rate.tick(Duration::from_secs(2));
+/// assert_eq!(rate.per_second(), [150.0, 150.0, 150.0]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Rate {
+    /// Counted since the previous tick
+    pending: u64,
+    averages: [f64; 3],
+    /// The first tick sets the averages instead of starting from zero
+    primed: bool,
+}
+
+impl Rate {
+    pub fn add(&mut self, amount: u64) {
+        self.pending += amount;
+    }
+
+    /// Folds what was counted over the `elapsed` time since the previous tick into the averages
+    pub fn tick(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return;
+        }
+        let current = self.pending as f64 / secs;
+        self.pending = 0;
+
+        for (average, window) in self.averages.iter_mut().zip(RATE_WINDOWS) {
+            if self.primed {
+                // The longer the tick compared to the window, the more it weighs
+                let weight = 1.0 - (-secs / window.as_secs_f64()).exp();
+                *average += weight * (current - *average);
+            } else {
+                *average = current;
+            }
+        }
+        self.primed = true;
+    }
+
+    /// Averages over each of [`RATE_WINDOWS`]
+    pub fn per_second(&self) -> [f64; 3] {
+        self.averages
+    }
+}
+
+/// Totals of one subject.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubjectStats {
+    pub received: u64,
+    pub bytes: u64,
+    pub published: u64,
+    pub failed: u64,
+    /// Messages received per second since the previous refresh
+    pub rate: f64,
+    /// `received` at the previous refresh
+    last_received: u64,
+}
+
+/// Stats of every subject seen so far, the one a message was read from or published to. Past
+/// [`MAX_SUBJECTS`], new subjects are folded into [`OTHER_SUBJECT`].
+///
+/// # Example
+///
+/// ```rust
+/// use naps::stats::{Outcome, SubjectTable};
+/// use std::time::Duration;
+///
+/// let mut subjects = SubjectTable::default();
+/// subjects.record("orders.created", 10, Outcome::Received);
+/// subjects.record("orders.created", 10, Outcome::Received);
+/// subjects.record("users.created", 5, Outcome::Received);
+/// subjects.refresh(Duration::from_secs(2));
+///
+/// let top = subjects.top(1);
+/// assert_eq!(top[0].0, "orders.created");
+/// assert_eq!(top[0].1.rate, 1.0);
+/// ```
+#[derive(Debug, Default)]
+pub struct SubjectTable(BTreeMap<String, SubjectStats>);
+
+impl SubjectTable {
+    pub fn record(&mut self, subject: &str, size: u64, outcome: Outcome) {
+        let subject = if self.0.len() < MAX_SUBJECTS || self.0.contains_key(subject) {
+            subject
+        } else {
+            OTHER_SUBJECT
+        };
+        let stats = self.0.entry(subject.to_string()).or_default();
+        match outcome {
+            Outcome::Received => {
+                stats.received += 1;
+                stats.bytes += size;
+            }
+            Outcome::Published => stats.published += 1,
+            Outcome::Failed => stats.failed += 1,
+        }
+    }
+
+    /// Computes the rates over the `elapsed` time since the previous refresh
+    pub fn refresh(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for stats in self.0.values_mut() {
+            let received = stats.received - stats.last_received;
+            stats.rate = if secs > 0.0 {
+                received as f64 / secs
+            } else {
+                0.0
+            };
+            stats.last_received = stats.received;
+        }
+    }
+
+    pub fn get(&self, subject: &str) -> Option<&SubjectStats> {
+        self.0.get(subject)
+    }
+
+    /// The `n` subjects with the highest rate, the busiest overall first among equals
+    pub fn top(&self, n: usize) -> Vec<(&str, &SubjectStats)> {
+        let mut subjects: Vec<(&str, &SubjectStats)> =
+            self.0.iter().map(|(k, v)| (k.as_str(), v)).collect();
+        subjects.sort_by(|a, b| {
+            b.1.rate
+                .total_cmp(&a.1.rate)
+                .then(b.1.received.cmp(&a.1.received))
+                .then(b.1.published.cmp(&a.1.published))
+        });
+        subjects.truncate(n);
+        subjects
+    }
+}
+
+/// Aggregates the events of every loop. `metrics` provides the queue depths shown on the
+/// dashboard.
 pub fn stats_loop(
-    silent: bool,
-    stats_rc: Receiver<u64>,
+    view: View,
+    metrics: Metrics,
+    stats_rc: Receiver<Event>,
     shutdown_arc: Arc<AtomicBool>,
 ) -> Result<()> {
+    let silent = view != View::Progress;
     let mut total_bytes = 0;
+    let mut total_errors = 0;
+    // Of the messages read from the source
+    let mut messages = Rate::default();
+    let mut bytes = Rate::default();
     let start = Instant::now();
-    let mut timer = Timer::new();
+    let mut timer = Timer::with_period(REFRESH_INTERVAL);
     let mut stderr = io::stderr();
+    let mut subjects = SubjectTable::default();
+    let mut refreshed = Instant::now();
+    let mut dashboard = match view {
+        View::Dashboard => Some(Dashboard::open()?),
+        _ => None,
+    };
+    // Shown at the bottom of the dashboard instead of scrolling it away
+    let mut notice = String::new();
 
     while !shutdown_arc.load(Ordering::Relaxed) {
-        let num_bytes = stats_rc.recv().unwrap();
-        total_bytes += num_bytes;
         timer.tick();
-        let rate_per_second = num_bytes as f64 / timer.delta.as_secs_f64();
-        if !silent && timer.ready {
+        if timer.ready {
             timer.ready = false;
-            output_progress(
-                &mut stderr,
-                total_bytes,
-                start.elapsed().as_secs().as_clock(),
-                rate_per_second,
-            );
+            let elapsed = refreshed.elapsed();
+            refreshed = Instant::now();
+            subjects.refresh(elapsed);
+            messages.tick(elapsed);
+            bytes.tick(elapsed);
+
+            let elapsed = start.elapsed().as_secs().as_clock();
+            if let Some(dashboard) = dashboard.as_mut() {
+                let (messages, bytes) = format_rates(&messages, &bytes);
+                let header = format!(
+                    "{} {} {} {} [{} errors]",
+                    total_bytes.as_hf_bytes(),
+                    elapsed,
+                    messages,
+                    bytes,
+                    total_errors
+                );
+                dashboard.draw(&header, &subjects, &metrics.queue_depths(), &notice)?;
+            } else if !silent {
+                output_progress(
+                    &mut stderr,
+                    total_bytes,
+                    elapsed,
+                    format_rates(&messages, &bytes),
+                    total_errors,
+                );
+            }
         }
-        if num_bytes == 0 {
-            break;
+
+        let event = match stats_rc.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let (subject, num_bytes, outcome) = match event {
+            Event::Message {
+                subject,
+                size,
+                outcome,
+            } => (subject, size, outcome),
+            Event::Disconnected(side) => {
+                notice = format!("{} disconnected, reconnecting", side);
+                if dashboard.is_none() {
+                    if !silent {
+                        eprintln!();
+                    }
+                    warn!(side = side, kind = "disconnected"; "{}", notice);
+                }
+                continue;
+            }
+            Event::Reconnected(side) => {
+                notice = format!("{} reconnected", side);
+                if dashboard.is_none() {
+                    if !silent {
+                        eprintln!();
+                    }
+                    info!(side = side; "{}", notice);
+                }
+                continue;
+            }
+        };
+        subjects.record(&subject, num_bytes, outcome);
+        match outcome {
+            Outcome::Received => {
+                total_bytes += num_bytes;
+                messages.add(1);
+                bytes.add(num_bytes);
+            }
+            Outcome::Published => {}
+            Outcome::Failed => total_errors += 1,
         }
     }
 
+    // Back to the regular screen before printing anything
+    drop(dashboard);
+
     if !silent {
         eprintln!();
     }
 
-    eprintln!("stats loop exited");
+    debug!("stats loop exited");
 
     Ok(())
 }
 
-fn output_progress(stderr: &mut Stderr, bytes: u64, elapsed: String, rate: f64) {
+/// Messages and bytes per second, averaged over each of [`RATE_WINDOWS`]
+fn format_rates(messages: &Rate, bytes: &Rate) -> (String, String) {
+    let [m1, m10, m60] = messages.per_second();
+    let [b1, b10, b60] = bytes.per_second().map(|b| (b.round() as u64).as_hf_bytes());
+    (
+        format!("[{:.1} {:.1} {:.1} msg/s]", m1, m10, m60),
+        format!("[{}/s {}/s {}/s]", b1, b10, b60),
+    )
+}
+
+fn output_progress(
+    stderr: &mut Stderr,
+    bytes: u64,
+    elapsed: String,
+    (message_rates, byte_rates): (String, String),
+    errors: u64,
+) {
     let bytes = style::style(format!("{} ", bytes.as_hf_bytes())).with(Color::Red);
     let elapsed = style::style(elapsed).with(Color::Green);
-    let rate = style::style(format!(" [{:.0}b/s]", rate)).with(Color::Blue);
+    let message_rates = style::style(format!(" {}", message_rates)).with(Color::Blue);
+    let byte_rates = style::style(format!(" {}", byte_rates)).with(Color::Blue);
+    let errors = style::style(format!(" [{} errors]", errors)).with(Color::Yellow);
     let _ = execute!(
         stderr,
         cursor::MoveToColumn(0),
         Clear(ClearType::CurrentLine),
         PrintStyledContent(bytes),
         PrintStyledContent(elapsed),
-        PrintStyledContent(rate),
+        PrintStyledContent(message_rates),
+        PrintStyledContent(byte_rates),
+        PrintStyledContent(errors),
     );
     let _ = stderr.flush();
 }
@@ -88,14 +388,15 @@ impl Clock for u64 {
     }
 }
 
-/// The HumanFriendlyBytes trait adds a `.as_hf_bytes()` method to `usize`
+/// The HumanFriendlyBytes trait adds a `.as_hf_bytes()` method to `u64`
 ///
 /// # Example
 /// Here is an example of how to use it.
 ///
 /// ```rust
 /// use naps::stats::HumanFriendlyBytes;
-/// assert_eq!(1025_usize.as_hf_bytes(), String::from("1 KB"))
+/// assert_eq!(1025_u64.as_hf_bytes(), String::from("1 KB"));
+/// assert_eq!(1536_u64.as_hf_bytes(), String::from("1.5 KB"));
 /// ```
 pub trait HumanFriendlyBytes {
     fn as_hf_bytes(&self) -> String;
@@ -103,23 +404,23 @@ pub trait HumanFriendlyBytes {
 
 impl HumanFriendlyBytes for u64 {
     fn as_hf_bytes(&self) -> String {
-        let byte_mul: Vec<(u64, &str)> = vec![
+        let byte_mul: [(u64, &str); 4] = [
             (u64::pow(1024, 4), "TB"),
             (u64::pow(1024, 3), "GB"),
             (u64::pow(1024, 2), "MB"),
             (u64::pow(1024, 1), "KB"),
         ];
 
-        let v = *self;
-
-        for (mul, unit) in byte_mul.iter() {
-            let c = v / mul;
-            if c > 0 {
-                return format!("{:.2} {}", c, unit);
+        for (mul, unit) in byte_mul {
+            if *self >= mul {
+                let value = format!("{:.2}", *self as f64 / mul as f64);
+                // 1.50 reads as 1.5, and 1.00 as 1
+                let value = value.trim_end_matches('0').trim_end_matches('.');
+                return format!("{} {}", value, unit);
             }
         }
 
-        return format!("{} B", v);
+        format!("{} B", self)
     }
 }
 
@@ -128,6 +429,9 @@ mod tests {
 
     use super::Clock;
     use super::HumanFriendlyBytes;
+    use super::{Outcome, Rate, SubjectTable};
+    use crate::metrics::{MAX_SUBJECTS, OTHER_SUBJECT};
+    use std::time::Duration;
 
     #[test]
     fn as_time_format() {
@@ -150,10 +454,80 @@ mod tests {
             (1025, "1 KB"),
             (u64::pow(1024, 2), "1 MB"),
             (u64::pow(1024, 3), "1 GB"),
+            (1536, "1.5 KB"),
+            (u64::pow(1024, 2) * 3 / 2, "1.5 MB"),
+            (1_234_567, "1.18 MB"),
+            (u64::pow(1024, 4) * 5 / 4, "1.25 TB"),
         ];
 
         for (input, output) in pairs {
             assert_eq!(input.as_hf_bytes(), output);
         }
     }
+
+    #[test]
+    fn subject_table_rates() {
+        let mut subjects = SubjectTable::default();
+        for _ in 0..4 {
+            subjects.record("orders.created", 100, Outcome::Received);
+        }
+        subjects.record("mirror.orders.created", 100, Outcome::Published);
+        subjects.record("users.created", 10, Outcome::Received);
+        subjects.record("users.created", 10, Outcome::Failed);
+        subjects.refresh(Duration::from_secs(2));
+
+        let orders = subjects.get("orders.created").unwrap();
+        assert_eq!((orders.received, orders.bytes, orders.rate), (4, 400, 2.0));
+        assert_eq!(subjects.get("users.created").unwrap().failed, 1);
+
+        let top: Vec<&str> = subjects
+            .top(2)
+            .iter()
+            .map(|(subject, _)| *subject)
+            .collect();
+        assert_eq!(top, vec!["orders.created", "users.created"]);
+
+        // Nothing new since the previous refresh
+        subjects.refresh(Duration::from_secs(1));
+        assert_eq!(subjects.get("orders.created").unwrap().rate, 0.0);
+    }
+
+    #[test]
+    fn subject_table_is_capped() {
+        let mut subjects = SubjectTable::default();
+        for i in 0..MAX_SUBJECTS + 2 {
+            subjects.record(&format!("orders.{}", i), 10, Outcome::Received);
+        }
+        subjects.record("orders.0", 10, Outcome::Received);
+
+        assert_eq!(subjects.get("orders.0").unwrap().received, 2);
+        let other = subjects.get(OTHER_SUBJECT).unwrap();
+        assert_eq!((other.received, other.bytes), (2, 20));
+        assert!(subjects.get(&format!("orders.{}", MAX_SUBJECTS)).is_none());
+    }
+
+    #[test]
+    fn rate_averages() {
+        let second = Duration::from_secs(1);
+        let mut rate = Rate::default();
+        for _ in 0..120 {
+            rate.add(100);
+            rate.tick(second);
+        }
+        for average in rate.per_second() {
+            assert!((average - 100.0).abs() < 1e-9);
+        }
+
+        // A quiet second weighs more on the shorter windows
+        rate.tick(second);
+        let [one, ten, sixty] = rate.per_second();
+        assert!((one - 100.0 * (-1.0_f64).exp()).abs() < 1e-9);
+        assert!((ten - 100.0 * (-0.1_f64).exp()).abs() < 1e-9);
+        assert!((sixty - 100.0 * (-1.0_f64 / 60.0).exp()).abs() < 1e-9);
+
+        // Nothing happens in no time
+        rate.add(5);
+        rate.tick(Duration::ZERO);
+        assert_eq!(rate.per_second(), [one, ten, sixty]);
+    }
 }
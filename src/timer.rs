@@ -1,33 +1,47 @@
 use std::time::{Duration, Instant};
 
+/// Tells when a period went by, as seen from regular calls to `tick`.
 pub struct Timer {
     pub last: Instant,
+    /// Time between the last two ticks
     pub delta: Duration,
     pub period: Duration,
+    /// Time left in the current period
     pub countdown: Duration,
+    /// A period ended, cleared by whoever acts on it
     pub ready: bool,
 }
 
 impl Timer {
     pub fn new() -> Self {
-        let now = Instant::now();
+        Self::with_period(Duration::from_millis(1000))
+    }
+
+    pub fn with_period(period: Duration) -> Self {
         Self {
-            last: now,
+            last: Instant::now(),
             delta: Duration::default(),
-            period: Duration::from_millis(1000),
-            countdown: Duration::default(),
-            ready: true,
+            period,
+            countdown: period,
+            ready: false,
         }
     }
 
     pub fn tick(&mut self) {
-        let now = Instant::now();
-        self.delta = now - self.last;
+        self.tick_at(Instant::now())
+    }
+
+    /// Same as `tick`, at a given time
+    pub fn tick_at(&mut self, now: Instant) {
+        self.delta = now.saturating_duration_since(self.last);
         self.last = now;
-        self.countdown = self.countdown.checked_sub(self.delta).unwrap_or_else(|| {
-            self.ready = true;
-            self.period
-        })
+        match self.countdown.checked_sub(self.delta) {
+            Some(left) if !left.is_zero() => self.countdown = left,
+            _ => {
+                self.ready = true;
+                self.countdown = self.period;
+            }
+        }
     }
 }
 
@@ -36,3 +50,28 @@ impl Default for Timer {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Timer;
+    use std::time::Duration;
+
+    #[test]
+    fn ready_once_per_period() {
+        let mut timer = Timer::with_period(Duration::from_secs(1));
+        let start = timer.last;
+
+        timer.tick_at(start + Duration::from_millis(400));
+        assert_eq!(timer.delta, Duration::from_millis(400));
+        assert!(!timer.ready);
+
+        timer.tick_at(start + Duration::from_millis(1000));
+        assert!(timer.ready);
+        assert_eq!(timer.countdown, Duration::from_secs(1));
+
+        timer.ready = false;
+        timer.tick_at(start + Duration::from_millis(1500));
+        assert!(!timer.ready);
+        assert_eq!(timer.countdown, Duration::from_millis(500));
+    }
+}
@@ -1,30 +1,481 @@
+use crate::ack::AckHandle;
+use crate::dead_letter::{DeadLetters, Stage, ORIGINAL_SUBJECT_HEADER};
+use crate::mapping::Mappings;
+use crate::metrics::RouteMetrics;
 use crate::msg::Msg;
-use crossbeam::channel::Receiver;
-use crossbeam::select;
+use crate::origin::Origin;
+use crate::shutdown::Drain;
+use crate::stats::{Event, Outcome};
+use crate::{debug, warn};
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
+use nats::Connection;
+use serde_json::Value;
+use std::collections::HashMap;
 use std::io::{ErrorKind, Result};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+/// Header JetStream uses to discard messages it has already stored
+pub const MSG_ID_HEADER: &str = "Nats-Msg-Id";
+
+/// Core NATS publishes acked with a single flush round trip
+const FLUSH_BATCH: usize = 256;
+
+/// How long to wait for new messages or acks before checking for timeouts and shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Publish into JetStream and wait for the server to confirm each message was stored.
+#[derive(Debug, Clone)]
+pub struct JetStreamTarget {
+    /// Messages published but not yet acknowledged by the server
+    pub max_in_flight: usize,
+    pub ack_timeout: Duration,
+    /// Attempts after the first one before giving up on a message
+    pub retries: usize,
+}
+
+/// How `write_loop` publishes messages.
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// Name of the route, for logs
+    pub route: String,
+    pub jetstream: Option<JetStreamTarget>,
+    pub mappings: Mappings,
+    /// Stamped on published messages, on bidirectional routes only
+    pub origin: Option<Origin>,
+    pub metrics: RouteMetrics,
+    /// Where messages the destination refused go
+    pub dead_letters: DeadLetters,
+}
+
+impl WriteOptions {
+    /// Last changes to the message before it is published. Returns the subject it was read
+    /// from if a mapping renamed it.
+    fn prepare(&self, msg: &mut Msg) -> Option<String> {
+        let original_subject = self
+            .mappings
+            .map(&msg.topic)
+            .map(|topic| std::mem::replace(&mut msg.topic, topic));
+        if let Some(origin) = &self.origin {
+            origin.stamp(&mut msg.headers);
+        }
+        original_subject
+    }
+
+    /// Gives up on a message the destination did not take. It is dead-lettered as it was
+    /// published, mapped and stamped, along with the subject it was read from.
+    fn dead_letter(&self, mut msg: Msg, original_subject: Option<String>, reason: &str) {
+        if let Some(subject) = original_subject {
+            msg.headers.insert(ORIGINAL_SUBJECT_HEADER, &subject);
+        }
+        self.dead_letters.send(msg, Stage::Publish, reason);
+    }
+}
+
+/// A core NATS publish waiting for the flush that confirms it
+struct Unflushed {
+    ack: Option<AckHandle>,
+    topic: String,
+    size: usize,
+    read_at: Instant,
+}
+
+/// Publishes everything received until the channel disconnects, which happens on shutdown once
+/// the previous stages are done, or until the drain expires.
 pub fn write_loop(
-    nats: String,
-    msg_rc: Receiver<Msg>,
-    shutdown_arc: Arc<AtomicBool>,
+    nc: Connection,
+    opts: WriteOptions,
+    msg_rc: &Receiver<Msg>,
+    stats_sc: Sender<Event>,
+    drain: Drain,
 ) -> Result<()> {
-    let nc = nats::connect(nats)?;
-    println!("target connected");
+    if let Some(target) = &opts.jetstream {
+        return write_jetstream(nc, target, &opts, msg_rc, stats_sc, drain);
+    }
 
-    while !shutdown_arc.load(Ordering::Relaxed) {
-        let msg = msg_rc.recv().unwrap();
+    // Published but not yet known to have reached the server
+    let mut unflushed: Vec<Unflushed> = Vec::new();
 
-        if let Err(e) = nc.publish(&msg.topic, &msg.data) {
-            println!("{}", e);
+    while !drain.is_expired() {
+        let mut msg = match msg_rc.recv_timeout(POLL_INTERVAL) {
+            Ok(msg) => msg,
+            Err(RecvTimeoutError::Timeout) => {
+                flush(&nc, &mut unflushed, &opts, &stats_sc, &drain)?;
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let original_subject = opts.prepare(&mut msg);
+
+        let headers = msg.headers.to_nats();
+        let mut failed = false;
+        // Set when the message is given up on
+        let mut refused = None;
+        // While the destination is away publishes land in the reconnect buffer. Once that is
+        // full the message is retried, so the channel fills up and holds back the rest.
+        loop {
+            match nc.publish_with_reply_or_headers(&msg.topic, None, headers.as_ref(), &msg.data) {
+                Ok(()) => {
+                    unflushed.push(Unflushed {
+                        ack: msg.ack.take(),
+                        topic: msg.topic.clone(),
+                        size: msg.data.len(),
+                        read_at: msg.read_at,
+                    });
+                    break;
+                }
+                Err(e) if e.kind() == ErrorKind::ConnectionAborted => return Err(e),
+                Err(e) => {
+                    if !failed {
+                        warn!(
+                            route = opts.route,
+                            subject = msg.topic,
+                            kind = e.kind();
+                            "cannot publish: {}",
+                            e
+                        );
+                        let event = Event::message(&msg.topic, msg.data.len(), Outcome::Failed);
+                        let _ = stats_sc.send(event);
+                        opts.metrics.publish_error(&msg.topic);
+                        failed = true;
+                    }
+                    // Oversized messages never fit, retrying them is pointless
+                    if e.kind() == ErrorKind::InvalidInput {
+                        refused = Some(e.to_string());
+                    }
+                    if refused.is_some() || drain.is_expired() {
+                        opts.metrics.dropped(&msg.topic);
+                        drain.dropped(1);
+                        break;
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+        }
+
+        if let Some(reason) = refused {
+            opts.dead_letter(msg, original_subject, &reason);
+        }
+
+        if msg_rc.is_empty() || unflushed.len() >= FLUSH_BATCH {
+            flush(&nc, &mut unflushed, &opts, &stats_sc, &drain)?;
+        }
+    }
+
+    flush(&nc, &mut unflushed, &opts, &stats_sc, &drain)?;
+    // Left behind by an expired drain or a failed last flush
+    drain.dropped(unflushed.len() + msg_rc.len());
+    unflushed
+        .drain(..)
+        .filter_map(|entry| entry.ack)
+        .for_each(AckHandle::nack);
+
+    debug!(route = opts.route; "write loop exited");
+
+    Ok(())
+}
+
+/// Core NATS has no publish confirmation, a flush round trip is the closest thing: once the
+/// server answers, every message published before it has been received.
+fn flush(
+    nc: &Connection,
+    unflushed: &mut Vec<Unflushed>,
+    opts: &WriteOptions,
+    stats_sc: &Sender<Event>,
+    drain: &Drain,
+) -> Result<()> {
+    if unflushed.is_empty() {
+        return Ok(());
+    }
+
+    match nc.flush() {
+        Ok(()) => {
+            drain.flushed(unflushed.len());
+            for entry in unflushed.drain(..) {
+                opts.metrics
+                    .published(&entry.topic, entry.size, entry.read_at);
+                let _ = stats_sc.send(Event::message(&entry.topic, entry.size, Outcome::Published));
+                if let Some(ack) = entry.ack {
+                    ack.ack();
+                }
+            }
+        }
+        Err(e) => {
             if e.kind() == ErrorKind::ConnectionAborted {
+                drain.dropped(unflushed.len());
+                unflushed
+                    .drain(..)
+                    .filter_map(|entry| entry.ack)
+                    .for_each(AckHandle::nack);
                 return Err(e);
             }
+            // Still in the reconnect buffer, acked by the first flush after reconnecting
+            warn!(route = opts.route, kind = e.kind(); "cannot flush: {}", e);
         }
     }
 
-    eprintln!("write loop exited");
+    Ok(())
+}
+
+struct Pending {
+    msg: Msg,
+    /// Before mapping, for dead letters
+    original_subject: Option<String>,
+    id: String,
+    sent_at: Instant,
+    attempts: usize,
+}
+
+/// Publishes with a reply subject per message and matches the PubAcks that come back on it,
+/// so up to `max_in_flight` messages wait for their ack at the same time.
+fn write_jetstream(
+    nc: Connection,
+    target: &JetStreamTarget,
+    opts: &WriteOptions,
+    msg_rc: &Receiver<Msg>,
+    stats_sc: Sender<Event>,
+    drain: Drain,
+) -> Result<()> {
+    let inbox = nc.new_inbox();
+    let acks = nc.subscribe(format!("{}.*", inbox).as_str())?;
+    let mut pending: HashMap<String, Pending> = HashMap::new();
+    let mut next_token: u64 = 0;
+    // Once the channel disconnected only the pending acks are waited for
+    let mut closed = false;
+
+    while !drain.is_expired() && !(closed && pending.is_empty()) {
+        if !closed && pending.len() < target.max_in_flight {
+            match msg_rc.recv_timeout(POLL_INTERVAL) {
+                Ok(mut msg) => {
+                    let original_subject = opts.prepare(&mut msg);
+                    // Keep the id set by the original publisher, if any
+                    let id = match msg.headers.get(MSG_ID_HEADER) {
+                        Some(id) => id.to_string(),
+                        None => msg.id.take().unwrap_or_else(nuid::next),
+                    };
+                    let reply = format!("{}.{}", inbox, next_token);
+                    next_token += 1;
+                    let entry = Pending {
+                        msg,
+                        original_subject,
+                        id,
+                        sent_at: Instant::now(),
+                        attempts: 0,
+                    };
+                    send(&nc, opts, &entry, &reply)?;
+                    pending.insert(reply, entry);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => closed = true,
+            }
+        } else if let Ok(ack) = acks.next_timeout(POLL_INTERVAL) {
+            confirm(&mut pending, ack, opts, &stats_sc, &drain);
+        }
+
+        while let Some(ack) = acks.try_next() {
+            confirm(&mut pending, ack, opts, &stats_sc, &drain);
+        }
+
+        retry_expired(&nc, target, opts, &mut pending, &stats_sc, &drain)?;
+    }
+
+    // Left behind by an expired drain, nacked on drop
+    drain.dropped(pending.len() + msg_rc.len());
+
+    debug!(route = opts.route; "write loop exited");
+
+    Ok(())
+}
+
+fn publish(nc: &Connection, msg: &Msg, id: &str, reply: &str) -> Result<()> {
+    let mut headers = msg.headers.clone();
+    headers.insert(MSG_ID_HEADER, id);
+    let headers = headers.to_nats();
+    nc.publish_with_reply_or_headers(&msg.topic, Some(reply), headers.as_ref(), &msg.data)
+}
+
+/// Publishes a pending message. Failures other than a closed connection, like a full reconnect
+/// buffer, are retried once the ack timeout expires without using up an attempt.
+fn send(nc: &Connection, opts: &WriteOptions, entry: &Pending, reply: &str) -> Result<bool> {
+    match publish(nc, &entry.msg, &entry.id, reply) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::ConnectionAborted => Err(e),
+        Err(e) => {
+            warn!(
+                route = opts.route,
+                subject = entry.msg,
+                id = entry.id,
+                kind = e.kind();
+                "cannot publish: {}",
+                e
+            );
+            Ok(false)
+        }
+    }
+}
+
+fn confirm(
+    pending: &mut HashMap<String, Pending>,
+    ack: nats::Message,
+    opts: &WriteOptions,
+    stats_sc: &Sender<Event>,
+    drain: &Drain,
+) {
+    let metrics = &opts.metrics;
+    let mut entry = match pending.remove(&ack.subject) {
+        Some(entry) => entry,
+        // Late ack for a message that was already retried or given up on
+        None => return,
+    };
+
+    match parse_pub_ack(&ack.data) {
+        Ok(()) => {
+            let (topic, size) = (&entry.msg.topic, entry.msg.data.len());
+            metrics.published(topic, size, entry.msg.read_at);
+            let _ = stats_sc.send(Event::message(topic, size, Outcome::Published));
+            entry.msg.ack();
+            drain.flushed(1);
+        }
+        Err(e) => {
+            warn!(
+                route = opts.route,
+                subject = entry.msg,
+                id = entry.id,
+                kind = "rejected";
+                "jetstream rejected the message: {}",
+                e
+            );
+            let size = entry.msg.data.len();
+            let _ = stats_sc.send(Event::message(&entry.msg.topic, size, Outcome::Failed));
+            metrics.publish_error(&entry.msg.topic);
+            metrics.dropped(&entry.msg.topic);
+            drain.dropped(1);
+            opts.dead_letter(entry.msg, entry.original_subject, &e);
+        }
+    }
+}
+
+fn retry_expired(
+    nc: &Connection,
+    target: &JetStreamTarget,
+    opts: &WriteOptions,
+    pending: &mut HashMap<String, Pending>,
+    stats_sc: &Sender<Event>,
+    drain: &Drain,
+) -> Result<()> {
+    let metrics = &opts.metrics;
+    let expired: Vec<String> = pending
+        .iter()
+        .filter(|(_, entry)| entry.sent_at.elapsed() >= target.ack_timeout)
+        .map(|(reply, _)| reply.clone())
+        .collect();
+
+    for reply in expired {
+        let mut entry = pending.remove(&reply).unwrap();
+
+        if entry.attempts >= target.retries {
+            warn!(
+                route = opts.route,
+                subject = entry.msg,
+                id = entry.id,
+                kind = "timeout";
+                "no ack from jetstream, giving up"
+            );
+            let size = entry.msg.data.len();
+            let _ = stats_sc.send(Event::message(&entry.msg.topic, size, Outcome::Failed));
+            metrics.publish_error(&entry.msg.topic);
+            metrics.dropped(&entry.msg.topic);
+            drain.dropped(1);
+            let reason = format!(
+                "no ack from jetstream after {} attempts",
+                entry.attempts + 1
+            );
+            opts.dead_letter(entry.msg, entry.original_subject, &reason);
+            continue;
+        }
+
+        // Same id on every attempt, so JetStream stores the message only once
+        if send(nc, opts, &entry, &reply)? {
+            entry.attempts += 1;
+        }
+        entry.sent_at = Instant::now();
+        pending.insert(reply, entry);
+    }
 
     Ok(())
 }
+
+/// Checks the body of a PubAck, which carries an `error` object when the message was not stored.
+fn parse_pub_ack(data: &[u8]) -> std::result::Result<(), String> {
+    if data.is_empty() {
+        // Empty status replies mean nothing is listening, i.e. no stream for the subject
+        return Err("no responders".to_string());
+    }
+
+    let value: Value = serde_json::from_slice(data).map_err(|e| e.to_string())?;
+
+    match value.get("error") {
+        None => Ok(()),
+        Some(error) => Err(error
+            .get("description")
+            .and_then(Value::as_str)
+            .unwrap_or("unknown error")
+            .to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_pub_ack, WriteOptions};
+    use crate::dead_letter::{DeadLetters, ORIGINAL_SUBJECT_HEADER, SUBJECT_HEADER};
+    use crate::mapping::{Mappings, SubjectMapping};
+    use crate::msg::Msg;
+    use crossbeam::channel::unbounded;
+
+    #[test]
+    fn pub_ack_parsing() {
+        assert_eq!(parse_pub_ack(br#"{"stream":"ORDERS","seq":7}"#), Ok(()));
+        assert_eq!(
+            parse_pub_ack(br#"{"stream":"ORDERS","seq":7,"duplicate":true}"#),
+            Ok(())
+        );
+        assert_eq!(
+            parse_pub_ack(br#"{"error":{"code":503,"description":"stream offline"}}"#),
+            Err("stream offline".to_string())
+        );
+        assert!(parse_pub_ack(b"").is_err());
+        assert!(parse_pub_ack(b"not json").is_err());
+    }
+
+    #[test]
+    fn dead_letters_keep_the_subject_before_mapping() {
+        let (dead_letter_sc, dead_letter_rc) = unbounded();
+        let mapping = SubjectMapping::new("orders.*", "eu.orders.{1}").unwrap();
+        let opts = WriteOptions {
+            mappings: Mappings(vec![mapping]),
+            dead_letters: DeadLetters::new("orders", dead_letter_sc),
+            ..Default::default()
+        };
+
+        let mut msg = Msg::new(vec![], "orders.created".to_string());
+        let original_subject = opts.prepare(&mut msg);
+        assert_eq!(msg.topic, "eu.orders.created");
+        opts.dead_letter(msg, original_subject, "maximum payload exceeded");
+        let dead_letter = dead_letter_rc.try_recv().unwrap();
+        assert_eq!(
+            dead_letter.headers.get(SUBJECT_HEADER),
+            Some("eu.orders.created")
+        );
+        assert_eq!(
+            dead_letter.headers.get(ORIGINAL_SUBJECT_HEADER),
+            Some("orders.created")
+        );
+
+        // Not renamed, nothing to add
+        let mut msg = Msg::new(vec![], "users.created".to_string());
+        let original_subject = opts.prepare(&mut msg);
+        opts.dead_letter(msg, original_subject, "maximum payload exceeded");
+        let dead_letter = dead_letter_rc.try_recv().unwrap();
+        assert_eq!(dead_letter.headers.get(ORIGINAL_SUBJECT_HEADER), None);
+    }
+}